@@ -0,0 +1,261 @@
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use async_trait::async_trait;
+use serde_json::{Map, Value};
+use tokio::io::{self, AsyncWriteExt};
+
+use crate::convert;
+use crate::types::{CniAttachment, CniContainerId, CniInterfaceName, CniName};
+
+static CACHE_KIND: &str = "cniCacheV1";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CniCacheKey {
+    pub network_name: CniName,
+    pub container_id: CniContainerId,
+    pub interface_name: CniInterfaceName,
+}
+
+impl CniCacheKey {
+    pub fn new(network_name: CniName, container_id: CniContainerId, interface_name: CniInterfaceName) -> Self {
+        Self {
+            network_name,
+            container_id,
+            interface_name,
+        }
+    }
+
+    /// The file name libcni uses for this attachment inside its "results" directory.
+    pub fn file_name(&self) -> String {
+        format!(
+            "{}-{}-{}",
+            self.network_name.as_ref(),
+            self.container_id.as_ref(),
+            self.interface_name.as_ref()
+        )
+    }
+}
+
+#[async_trait]
+pub trait CniCache {
+    async fn get(&self, key: &CniCacheKey) -> Result<Option<CniAttachment>, io::Error>;
+
+    async fn insert(&self, key: &CniCacheKey, attachment: &CniAttachment) -> Result<(), io::Error>;
+
+    async fn remove(&self, key: &CniCacheKey) -> Result<(), io::Error>;
+}
+
+/// A cache that only lives as long as the process does, mostly useful for testing.
+#[derive(Debug, Default)]
+pub struct MemoryCniCache {
+    entries: Mutex<HashMap<CniCacheKey, CniAttachment>>,
+}
+
+impl MemoryCniCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl CniCache for MemoryCniCache {
+    async fn get(&self, key: &CniCacheKey) -> Result<Option<CniAttachment>, io::Error> {
+        Ok(lock_entries(&self.entries)?.get(key).cloned())
+    }
+
+    async fn insert(&self, key: &CniCacheKey, attachment: &CniAttachment) -> Result<(), io::Error> {
+        lock_entries(&self.entries)?.insert(key.clone(), attachment.clone());
+        Ok(())
+    }
+
+    async fn remove(&self, key: &CniCacheKey) -> Result<(), io::Error> {
+        lock_entries(&self.entries)?.remove(key);
+        Ok(())
+    }
+}
+
+fn lock_entries(
+    entries: &Mutex<HashMap<CniCacheKey, CniAttachment>>,
+) -> Result<std::sync::MutexGuard<'_, HashMap<CniCacheKey, CniAttachment>>, io::Error> {
+    entries
+        .lock()
+        .map_err(|_| io::Error::other("Memory cache mutex was poisoned"))
+}
+
+/// A cache that stores results on disk in the same layout libcni uses, so that the directory
+/// can be shared with other CNI runtimes. The default libcni directory is "/var/lib/cni".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileCniCache {
+    pub directory_path: PathBuf,
+}
+
+impl FileCniCache {
+    pub fn new(directory_path: impl Into<PathBuf>) -> Self {
+        Self {
+            directory_path: directory_path.into(),
+        }
+    }
+
+    fn results_path(&self) -> PathBuf {
+        self.directory_path.join("results")
+    }
+
+    fn entry_path(&self, key: &CniCacheKey) -> PathBuf {
+        self.results_path().join(key.file_name())
+    }
+}
+
+impl Default for FileCniCache {
+    fn default() -> Self {
+        Self::new("/var/lib/cni")
+    }
+}
+
+#[async_trait]
+impl CniCache for FileCniCache {
+    async fn get(&self, key: &CniCacheKey) -> Result<Option<CniAttachment>, io::Error> {
+        let content = match tokio::fs::read_to_string(self.entry_path(key)).await {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let mut entry: Map<String, Value> = serde_json::from_str(&content)?;
+        match entry.remove("result") {
+            Some(result) => convert::parse_attachment(result)
+                .map(Some)
+                .map_err(|err| io::Error::new(ErrorKind::InvalidData, format!("{err:?}"))),
+            None => Ok(None),
+        }
+    }
+
+    async fn insert(&self, key: &CniCacheKey, attachment: &CniAttachment) -> Result<(), io::Error> {
+        let mut entry = Map::new();
+        entry.insert("kind".into(), Value::String(CACHE_KIND.into()));
+        entry.insert("containerId".into(), Value::String(key.container_id.as_ref().into()));
+        entry.insert("ifName".into(), Value::String(key.interface_name.as_ref().into()));
+        entry.insert("networkName".into(), Value::String(key.network_name.as_ref().into()));
+        entry.insert("result".into(), serde_json::to_value(attachment)?);
+
+        tokio::fs::create_dir_all(self.results_path()).await?;
+        write_atomically(&self.entry_path(key), serde_json::to_string(&Value::Object(entry))?).await
+    }
+
+    async fn remove(&self, key: &CniCacheKey) -> Result<(), io::Error> {
+        match tokio::fs::remove_file(self.entry_path(key)).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Write through a temporary file next to the destination, named uniquely so that concurrent writers of the same
+/// entry, in this process or another, never share one, and rename it into place.
+async fn write_atomically(path: &Path, content: String) -> Result<(), io::Error> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut temporary_path = path.as_os_str().to_owned();
+    temporary_path.push(format!(
+        ".{}-{}.tmp",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let result = async {
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temporary_path)
+            .await?;
+        file.write_all(content.as_bytes()).await?;
+        file.flush().await?;
+        drop(file);
+        tokio::fs::rename(&temporary_path, path).await
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&temporary_path).await;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cache::{CniCache, CniCacheKey, FileCniCache, MemoryCniCache},
+        testing::TemporaryDirectory,
+        types::{CniAttachment, CniContainerId, CniInterfaceName, CniName, CniVersion},
+    };
+
+    fn key() -> CniCacheKey {
+        CniCacheKey::new(
+            CniName::new("testnet").unwrap(),
+            CniContainerId::new("container").unwrap(),
+            CniInterfaceName::new("eth0").unwrap(),
+        )
+    }
+
+    fn attachment() -> CniAttachment {
+        CniAttachment {
+            cni_version: CniVersion::new(1, 0, 0),
+            interfaces: Vec::new(),
+            ips: Vec::new(),
+            routes: Vec::new(),
+            dns: None,
+        }
+    }
+
+    #[test]
+    fn key_file_name_matches_libcni() {
+        assert_eq!(key().file_name(), "testnet-container-eth0");
+    }
+
+    #[tokio::test]
+    async fn memory_cache_round_trips() {
+        let cache = MemoryCniCache::new();
+        assert_eq!(cache.get(&key()).await.unwrap(), None);
+        cache.insert(&key(), &attachment()).await.unwrap();
+        assert_eq!(cache.get(&key()).await.unwrap(), Some(attachment()));
+        cache.remove(&key()).await.unwrap();
+        assert_eq!(cache.get(&key()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn file_cache_round_trips() {
+        let directory_path = TemporaryDirectory::new("file-cache");
+        let cache = FileCniCache::new(directory_path.to_path_buf());
+        assert_eq!(cache.get(&key()).await.unwrap(), None);
+
+        cache.insert(&key(), &attachment()).await.unwrap();
+        assert!(directory_path.join("results").join(key().file_name()).exists());
+        assert_eq!(cache.get(&key()).await.unwrap(), Some(attachment()));
+
+        cache.remove(&key()).await.unwrap();
+        assert_eq!(cache.get(&key()).await.unwrap(), None);
+        cache.remove(&key()).await.unwrap();
+    }
+    #[tokio::test(flavor = "multi_thread")]
+    async fn file_cache_survives_concurrent_inserts() {
+        let directory_path = TemporaryDirectory::new("file-cache-concurrent");
+        let cache = FileCniCache::new(directory_path.to_path_buf());
+
+        let inserts = (0..16).map(|_| {
+            let cache = cache.clone();
+            tokio::spawn(async move { cache.insert(&key(), &attachment()).await })
+        });
+        for insert in inserts.collect::<Vec<_>>() {
+            insert.await.unwrap().unwrap();
+        }
+
+        assert_eq!(cache.get(&key()).await.unwrap(), Some(attachment()));
+        assert_eq!(std::fs::read_dir(directory_path.join("results")).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,202 @@
+use std::{collections::BTreeMap, net::IpAddr};
+
+use cidr::{IpCidr, IpInet};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// Arguments the runtime supplies for capabilities, following the CNI conventions. Each argument is only passed to a
+/// plugin inside its runtimeConfig if the plugin declares the matching capability as enabled.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct CniCapabilityArgs {
+    #[serde(rename = "portMappings", skip_serializing_if = "Option::is_none")]
+    port_mappings: Option<Vec<CniPortMapping>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bandwidth: Option<CniBandwidth>,
+    #[serde(rename = "ipRanges", skip_serializing_if = "Option::is_none")]
+    ip_ranges: Option<Vec<Vec<CniIpRange>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ips: Option<Vec<IpInet>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mac: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns: Option<CniDnsCapability>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aliases: Option<BTreeMap<String, Vec<String>>>,
+    #[serde(rename = "cgroupPath", skip_serializing_if = "Option::is_none")]
+    cgroup_path: Option<String>,
+    #[serde(rename = "infinibandGUID", skip_serializing_if = "Option::is_none")]
+    infiniband_guid: Option<String>,
+    #[serde(rename = "deviceID", skip_serializing_if = "Option::is_none")]
+    device_id: Option<String>,
+    #[serde(flatten)]
+    custom: Map<String, Value>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CniPortMapping {
+    #[serde(rename = "hostPort")]
+    pub host_port: u16,
+    #[serde(rename = "containerPort")]
+    pub container_port: u16,
+    pub protocol: String,
+    #[serde(rename = "hostIP", skip_serializing_if = "Option::is_none")]
+    pub host_ip: Option<IpAddr>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CniBandwidth {
+    #[serde(rename = "ingressRate", skip_serializing_if = "Option::is_none")]
+    pub ingress_rate: Option<u64>,
+    #[serde(rename = "ingressBurst", skip_serializing_if = "Option::is_none")]
+    pub ingress_burst: Option<u64>,
+    #[serde(rename = "egressRate", skip_serializing_if = "Option::is_none")]
+    pub egress_rate: Option<u64>,
+    #[serde(rename = "egressBurst", skip_serializing_if = "Option::is_none")]
+    pub egress_burst: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CniIpRange {
+    pub subnet: IpCidr,
+    #[serde(rename = "rangeStart", skip_serializing_if = "Option::is_none")]
+    pub range_start: Option<IpAddr>,
+    #[serde(rename = "rangeEnd", skip_serializing_if = "Option::is_none")]
+    pub range_end: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gateway: Option<IpAddr>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CniDnsCapability {
+    pub servers: Vec<IpAddr>,
+    pub searches: Vec<String>,
+    pub options: Vec<String>,
+}
+
+impl CniCapabilityArgs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn port_mappings(&mut self, port_mappings: Vec<CniPortMapping>) -> &mut Self {
+        self.port_mappings = Some(port_mappings);
+        self
+    }
+
+    pub fn bandwidth(&mut self, bandwidth: CniBandwidth) -> &mut Self {
+        self.bandwidth = Some(bandwidth);
+        self
+    }
+
+    pub fn ip_ranges(&mut self, ip_ranges: Vec<Vec<CniIpRange>>) -> &mut Self {
+        self.ip_ranges = Some(ip_ranges);
+        self
+    }
+
+    pub fn ips(&mut self, ips: Vec<IpInet>) -> &mut Self {
+        self.ips = Some(ips);
+        self
+    }
+
+    pub fn mac(&mut self, mac: impl Into<String>) -> &mut Self {
+        self.mac = Some(mac.into());
+        self
+    }
+
+    pub fn dns(&mut self, dns: CniDnsCapability) -> &mut Self {
+        self.dns = Some(dns);
+        self
+    }
+
+    pub fn aliases(&mut self, aliases: BTreeMap<String, Vec<String>>) -> &mut Self {
+        self.aliases = Some(aliases);
+        self
+    }
+
+    pub fn cgroup_path(&mut self, cgroup_path: impl Into<String>) -> &mut Self {
+        self.cgroup_path = Some(cgroup_path.into());
+        self
+    }
+
+    pub fn infiniband_guid(&mut self, infiniband_guid: impl Into<String>) -> &mut Self {
+        self.infiniband_guid = Some(infiniband_guid.into());
+        self
+    }
+
+    pub fn device_id(&mut self, device_id: impl Into<String>) -> &mut Self {
+        self.device_id = Some(device_id.into());
+        self
+    }
+
+    /// Supply an argument for a capability that has no typed equivalent here.
+    pub fn custom(&mut self, capability: impl Into<String>, value: Value) -> &mut Self {
+        self.custom.insert(capability.into(), value);
+        self
+    }
+
+    /// Build the runtimeConfig for a plugin, keeping only the arguments whose capability the plugin enables.
+    pub fn to_runtime_config(
+        &self,
+        capabilities: &Map<String, Value>,
+    ) -> Result<Map<String, Value>, serde_json::Error> {
+        let arguments = match serde_json::to_value(self)? {
+            Value::Object(arguments) => arguments,
+            _ => Map::new(),
+        };
+
+        Ok(arguments
+            .into_iter()
+            .filter(|(capability, _)| capabilities.get(capability) == Some(&Value::Bool(true)))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use crate::capabilities::{CniBandwidth, CniCapabilityArgs, CniPortMapping};
+
+    fn capability_args() -> CniCapabilityArgs {
+        let mut capability_args = CniCapabilityArgs::new();
+        capability_args
+            .port_mappings(vec![CniPortMapping {
+                host_port: 8080,
+                container_port: 80,
+                protocol: "tcp".into(),
+                host_ip: None,
+            }])
+            .bandwidth(CniBandwidth {
+                ingress_rate: Some(1000),
+                ingress_burst: None,
+                egress_rate: None,
+                egress_burst: None,
+            })
+            .mac("0a:58:0a:00:00:02")
+            .custom("io.kubernetes.cri.pod-annotations", json!({ "key": "value" }));
+        capability_args
+    }
+
+    #[test]
+    fn runtime_config_only_contains_enabled_capabilities() {
+        let capabilities =
+            json!({ "portMappings": true, "bandwidth": false, "ips": true, "io.kubernetes.cri.pod-annotations": true });
+        let runtime_config = capability_args()
+            .to_runtime_config(capabilities.as_object().unwrap())
+            .unwrap();
+
+        assert_eq!(
+            Value::Object(runtime_config),
+            json!({
+                "portMappings": [{ "hostPort": 8080, "containerPort": 80, "protocol": "tcp" }],
+                "io.kubernetes.cri.pod-annotations": { "key": "value" }
+            })
+        );
+    }
+
+    #[test]
+    fn runtime_config_is_empty_without_capabilities() {
+        let runtime_config = capability_args().to_runtime_config(&Default::default()).unwrap();
+        assert!(runtime_config.is_empty());
+    }
+}
@@ -0,0 +1,401 @@
+use std::net::IpAddr;
+
+use cidr::IpInet;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::types::{
+    CniAttachment, CniAttachmentDns, CniAttachmentInterface, CniAttachmentIp, CniAttachmentRoute, CniValidationError,
+    CniVersion,
+};
+
+/// The first version whose result shape matches [CniAttachment]. Older results are upgraded to this version.
+pub const CURRENT_RESULT_VERSION: CniVersion = CniVersion::new(1, 0, 0);
+/// The first version that reports a list of IPs and interfaces instead of "ip4" and "ip6" objects.
+const LIST_RESULT_VERSION: CniVersion = CniVersion::new(0, 3, 0);
+
+#[derive(Debug)]
+pub enum CniConversionError {
+    SerdeError(serde_json::Error),
+    RootIsNotObject,
+    MissingVersion,
+    MalformedVersion(CniValidationError),
+}
+
+/// Parse a result produced by a plugin speaking any published CNI version. Results older than 1.0.0 are upgraded to
+/// the current shape and have their version set to 1.0.0, like libcni does.
+pub fn parse_attachment(value: Value) -> Result<CniAttachment, CniConversionError> {
+    let cni_version = result_version(&value)?;
+
+    if cni_version >= CURRENT_RESULT_VERSION {
+        return serde_json::from_value(value).map_err(CniConversionError::SerdeError);
+    }
+
+    if cni_version >= LIST_RESULT_VERSION {
+        let attachment: ListAttachment = serde_json::from_value(value).map_err(CniConversionError::SerdeError)?;
+        return Ok(attachment.upgrade());
+    }
+
+    let attachment: LegacyAttachment = serde_json::from_value(value).map_err(CniConversionError::SerdeError)?;
+    Ok(attachment.upgrade())
+}
+
+/// Serialize an attachment in the result shape of the given CNI version. Down-converting to 0.2.0 and earlier keeps
+/// only the first address of each IP family, since those versions cannot express more.
+pub fn attachment_to_value(attachment: &CniAttachment, cni_version: CniVersion) -> Result<Value, CniConversionError> {
+    let serialized = if cni_version >= CURRENT_RESULT_VERSION {
+        let mut attachment = attachment.clone();
+        attachment.cni_version = cni_version;
+        serde_json::to_value(attachment)
+    } else if cni_version >= LIST_RESULT_VERSION {
+        serde_json::to_value(ListAttachment::downgrade(attachment, cni_version))
+    } else {
+        serde_json::to_value(LegacyAttachment::downgrade(attachment, cni_version))
+    };
+
+    serialized.map_err(CniConversionError::SerdeError)
+}
+
+/// Convert a result of any published CNI version into the result shape of the given CNI version.
+pub fn convert_attachment(value: Value, cni_version: CniVersion) -> Result<Value, CniConversionError> {
+    attachment_to_value(&parse_attachment(value)?, cni_version)
+}
+
+fn result_version(value: &Value) -> Result<CniVersion, CniConversionError> {
+    let cni_version = value
+        .as_object()
+        .ok_or(CniConversionError::RootIsNotObject)?
+        .get("cniVersion")
+        .and_then(|cni_version| cni_version.as_str())
+        .ok_or(CniConversionError::MissingVersion)?;
+    CniVersion::parse(cni_version).map_err(CniConversionError::MalformedVersion)
+}
+
+fn ip_version(address: &IpInet) -> String {
+    match address {
+        IpInet::V4(_) => "4".into(),
+        IpInet::V6(_) => "6".into(),
+    }
+}
+
+// 0.3.x and 0.4.0
+
+#[derive(Serialize, Deserialize)]
+struct ListAttachment {
+    #[serde(rename = "cniVersion")]
+    cni_version: CniVersion,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    interfaces: Vec<ListInterface>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ips: Vec<ListIp>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    routes: Vec<ListRoute>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns: Option<CniAttachmentDns>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ListInterface {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mac: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sandbox: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ListIp {
+    version: String,
+    address: IpInet,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gateway: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interface: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ListRoute {
+    dst: IpInet,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gw: Option<IpAddr>,
+}
+
+impl ListAttachment {
+    fn upgrade(self) -> CniAttachment {
+        CniAttachment {
+            cni_version: CURRENT_RESULT_VERSION,
+            interfaces: self
+                .interfaces
+                .into_iter()
+                .map(|interface| CniAttachmentInterface {
+                    name: interface.name,
+                    mac: interface.mac,
+                    mtu: None,
+                    sandbox: interface.sandbox,
+                    socket_path: None,
+                    pci_id: None,
+                })
+                .collect(),
+            ips: self
+                .ips
+                .into_iter()
+                .map(|ip| CniAttachmentIp {
+                    address: ip.address,
+                    gateway: ip.gateway,
+                    interface: ip.interface,
+                })
+                .collect(),
+            routes: self.routes.into_iter().map(ListRoute::upgrade).collect(),
+            dns: self.dns,
+        }
+    }
+
+    fn downgrade(attachment: &CniAttachment, cni_version: CniVersion) -> Self {
+        ListAttachment {
+            cni_version,
+            interfaces: attachment
+                .interfaces
+                .iter()
+                .map(|interface| ListInterface {
+                    name: interface.name.clone(),
+                    mac: interface.mac.clone(),
+                    sandbox: interface.sandbox.clone(),
+                })
+                .collect(),
+            ips: attachment
+                .ips
+                .iter()
+                .map(|ip| ListIp {
+                    version: ip_version(&ip.address),
+                    address: ip.address,
+                    gateway: ip.gateway,
+                    interface: ip.interface,
+                })
+                .collect(),
+            routes: attachment.routes.iter().map(ListRoute::downgrade).collect(),
+            dns: attachment.dns.clone(),
+        }
+    }
+}
+
+impl ListRoute {
+    fn upgrade(self) -> CniAttachmentRoute {
+        CniAttachmentRoute {
+            dst: self.dst,
+            gw: self.gw,
+            mtu: None,
+            advmss: None,
+            priority: None,
+            table: None,
+            scope: None,
+        }
+    }
+
+    fn downgrade(route: &CniAttachmentRoute) -> Self {
+        ListRoute {
+            dst: route.dst,
+            gw: route.gw,
+        }
+    }
+}
+
+// 0.1.0 and 0.2.0
+
+#[derive(Serialize, Deserialize)]
+struct LegacyAttachment {
+    #[serde(rename = "cniVersion")]
+    cni_version: CniVersion,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ip4: Option<LegacyIpConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ip6: Option<LegacyIpConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns: Option<CniAttachmentDns>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LegacyIpConfig {
+    ip: IpInet,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gateway: Option<IpAddr>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    routes: Vec<ListRoute>,
+}
+
+impl LegacyAttachment {
+    fn upgrade(self) -> CniAttachment {
+        let mut ips = Vec::new();
+        let mut routes = Vec::new();
+
+        for ip_config in [self.ip4, self.ip6].into_iter().flatten() {
+            ips.push(CniAttachmentIp {
+                address: ip_config.ip,
+                gateway: ip_config.gateway,
+                interface: None,
+            });
+            routes.extend(ip_config.routes.into_iter().map(ListRoute::upgrade));
+        }
+
+        CniAttachment {
+            cni_version: CURRENT_RESULT_VERSION,
+            interfaces: Vec::new(),
+            ips,
+            routes,
+            dns: self.dns,
+        }
+    }
+
+    fn downgrade(attachment: &CniAttachment, cni_version: CniVersion) -> Self {
+        let ip_config = |is_ipv4: bool| {
+            let ip = attachment.ips.iter().find(|ip| ip.address.is_ipv4() == is_ipv4)?;
+            Some(LegacyIpConfig {
+                ip: ip.address,
+                gateway: ip.gateway,
+                routes: attachment
+                    .routes
+                    .iter()
+                    .filter(|route| route.dst.is_ipv4() == is_ipv4)
+                    .map(ListRoute::downgrade)
+                    .collect(),
+            })
+        };
+
+        LegacyAttachment {
+            cni_version,
+            ip4: ip_config(true),
+            ip6: ip_config(false),
+            dns: attachment.dns.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use crate::{
+        convert::{attachment_to_value, convert_attachment, parse_attachment, CniConversionError},
+        types::CniVersion,
+    };
+
+    fn legacy_result() -> Value {
+        json!({
+            "cniVersion": "0.2.0",
+            "ip4": {
+                "ip": "10.0.0.2/24",
+                "gateway": "10.0.0.1",
+                "routes": [{ "dst": "0.0.0.0/0", "gw": "10.0.0.1" }]
+            },
+            "ip6": { "ip": "fd00::2/64" },
+            "dns": { "nameservers": ["10.0.0.1"] }
+        })
+    }
+
+    fn list_result() -> Value {
+        json!({
+            "cniVersion": "0.4.0",
+            "interfaces": [{ "name": "eth0", "mac": "0a:58:0a:00:00:02", "sandbox": "/var/run/netns/test" }],
+            "ips": [
+                { "version": "4", "address": "10.0.0.2/24", "gateway": "10.0.0.1", "interface": 0 },
+                { "version": "4", "address": "10.0.1.2/24", "interface": 0 }
+            ],
+            "routes": [{ "dst": "0.0.0.0/0" }]
+        })
+    }
+
+    #[test]
+    fn parses_legacy_results() {
+        let attachment = parse_attachment(legacy_result()).unwrap();
+        assert_eq!(attachment.cni_version, CniVersion::new(1, 0, 0));
+        assert!(attachment.interfaces.is_empty());
+        assert_eq!(attachment.ips.len(), 2);
+        assert_eq!(attachment.ips[0].address.to_string(), "10.0.0.2/24");
+        assert_eq!(attachment.ips[0].interface, None);
+        assert_eq!(attachment.ips[1].address.to_string(), "fd00::2/64");
+        assert_eq!(attachment.routes.len(), 1);
+        assert_eq!(attachment.dns.unwrap().nameservers.len(), 1);
+    }
+
+    #[test]
+    fn parses_list_results() {
+        let attachment = parse_attachment(list_result()).unwrap();
+        assert_eq!(attachment.cni_version, CniVersion::new(1, 0, 0));
+        assert_eq!(attachment.interfaces[0].sandbox.as_deref(), Some("/var/run/netns/test"));
+        assert_eq!(attachment.ips.len(), 2);
+        assert_eq!(attachment.ips[1].gateway, None);
+        assert_eq!(attachment.ips[1].interface, Some(0));
+    }
+
+    #[test]
+    fn keeps_current_results_as_is() {
+        let value = json!({
+            "cniVersion": "1.1.0",
+            "interfaces": [{ "name": "eth0", "mtu": 1500 }],
+            "ips": [{ "address": "10.0.0.2/24", "interface": 0 }],
+            "routes": [{ "dst": "0.0.0.0/0", "priority": 10 }]
+        });
+        let attachment = parse_attachment(value.clone()).unwrap();
+        assert_eq!(attachment.cni_version, CniVersion::new(1, 1, 0));
+        assert_eq!(
+            attachment_to_value(&attachment, CniVersion::new(1, 1, 0)).unwrap(),
+            value
+        );
+    }
+
+    #[test]
+    fn down_converts_to_list_results() {
+        let converted = convert_attachment(legacy_result(), CniVersion::new(0, 3, 1)).unwrap();
+        assert_eq!(
+            converted,
+            json!({
+                "cniVersion": "0.3.1",
+                "ips": [
+                    { "version": "4", "address": "10.0.0.2/24", "gateway": "10.0.0.1" },
+                    { "version": "6", "address": "fd00::2/64" }
+                ],
+                "routes": [{ "dst": "0.0.0.0/0", "gw": "10.0.0.1" }],
+                "dns": { "nameservers": ["10.0.0.1"], "search": [], "options": [] }
+            })
+        );
+    }
+
+    #[test]
+    fn down_converts_to_legacy_results() {
+        let converted = convert_attachment(list_result(), CniVersion::new(0, 2, 0)).unwrap();
+        assert_eq!(
+            converted,
+            json!({
+                "cniVersion": "0.2.0",
+                "ip4": {
+                    "ip": "10.0.0.2/24",
+                    "gateway": "10.0.0.1",
+                    "routes": [{ "dst": "0.0.0.0/0" }]
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn round_trips_between_versions() {
+        for cni_version in ["0.3.0", "0.3.1", "0.4.0", "1.0.0"] {
+            let cni_version = CniVersion::parse(cni_version).unwrap();
+            let converted = convert_attachment(list_result(), cni_version).unwrap();
+            assert_eq!(
+                parse_attachment(converted).unwrap(),
+                parse_attachment(list_result()).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_results_without_version() {
+        assert!(matches!(
+            parse_attachment(json!({ "ips": [] })),
+            Err(CniConversionError::MissingVersion)
+        ));
+        assert!(matches!(
+            parse_attachment(json!([])),
+            Err(CniConversionError::RootIsNotObject)
+        ));
+    }
+}
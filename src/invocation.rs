@@ -1,20 +1,33 @@
 use std::{
     collections::HashMap,
+    ffi::{CStr, OsStr},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::{fs::PermissionsExt, process::CommandExt},
+    },
     path::{Path, PathBuf},
-    process::Stdio,
+    process::{Command as StdCommand, Output, Stdio},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
 };
 
 use async_trait::async_trait;
+use inotify::{EventMask, Inotify, WatchMask};
 use tokio::{
     io::{self, AsyncWriteExt},
     process::Command,
 };
 
 use crate::{
+    capabilities::CniCapabilityArgs,
+    convert::CniConversionError,
     plugins::{CniPlugin, CniPluginList},
     types::{
-        CniAttachment, CniContainerId, CniError, CniInterfaceName, CniName, CniNetworkNamespace, CniValidAttachment,
-        CniVersion, CniVersionObject,
+        CniArg, CniAttachment, CniContainerId, CniError, CniInterfaceName, CniName, CniNetworkNamespace, CniOperation,
+        CniValidAttachment, CniValidationError, CniVersion, CniVersionObject,
     },
 };
 
@@ -22,15 +35,78 @@ use crate::{
 pub struct CniInvocationResult {
     pub attachment: Option<CniAttachment>,
     pub version_objects: HashMap<String, CniVersionObject>,
+    pub skip_reason: Option<CniSkipReason>,
+}
+
+/// Why an invocation was skipped by the runtime without executing any plugin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CniSkipReason {
+    CheckDisabled,
+    GarbageCollectDisabled,
 }
 
 #[derive(Debug)]
 pub enum CniInvocationError {
-    PluginNotFoundByLocator,
+    PluginNotFoundByLocator(CniLocatorError),
     InvokerFailed(io::Error),
     JsonOperationFailed(serde_json::Error),
-    PluginProducedUnrecognizableOutput(String),
-    PluginProducedError(CniError),
+    /// The plugin's output couldn't be interpreted as a result or, when it exited unsuccessfully, as an error.
+    PluginProducedUnrecognizableOutput {
+        exit_code: Option<i32>,
+        stdout: String,
+        stderr: String,
+    },
+    /// The plugin exited unsuccessfully and reported an error on stdout. Its stderr is kept for diagnostics.
+    PluginProducedError {
+        error: CniError,
+        stderr: String,
+    },
+    CacheFailed(io::Error),
+    ConversionFailed(CniConversionError),
+    /// ADD and CHECK need a usable network namespace, so it is validated before any plugin is executed. DEL
+    /// deliberately skips this, as it must succeed even if the namespace is already gone.
+    MalformedNetworkNamespace(CniValidationError),
+    NoCommonCniVersion {
+        plugin_type: String,
+    },
+    /// The operation doesn't exist in the effective CNI version, so no plugin was executed. The specification
+    /// expects the runtime to fall back as follows:
+    /// - CHECK before 0.4.0 must not be performed at all, so this is a hard error.
+    /// - STATUS before 1.1.0 should be treated as the network being ready.
+    /// - GC before 1.1.0 should be replaced by a DEL of every cached attachment that is no longer valid.
+    OperationNotSupportedByVersion {
+        operation: CniOperation,
+        cni_version: CniVersion,
+    },
+    /// The plugin ran longer than its timeout and was killed, unless it was escalated to a user this process can't
+    /// signal, such as root through su, sudo or pkexec, in which case it may still be running.
+    TimedOut {
+        plugin_type: String,
+        timeout: Duration,
+    },
+    /// [CniInvocationArguments::paths] differs from the directories the locator searches, so plugins would find
+    /// their delegates elsewhere than the runtime found them. No plugin was executed.
+    ConflictingPluginPaths {
+        paths: Vec<PathBuf>,
+        search_paths: Vec<PathBuf>,
+    },
+    /// An ADD failed with rollback enabled, so the plugins that had already succeeded were deleted again.
+    AddRolledBack {
+        failure: Box<CniPluginInvocationError>,
+        rollback_failures: Vec<CniPluginInvocationError>,
+    },
+    /// One or more plugins failed during a best-effort DEL or GC, which still invoked every plugin.
+    PluginsFailed {
+        failures: Vec<CniPluginInvocationError>,
+        succeeded_plugins: Vec<String>,
+    },
+}
+
+/// An invocation error attributed to the plugin that caused it.
+#[derive(Debug)]
+pub struct CniPluginInvocationError {
+    pub plugin_type: String,
+    pub error: CniInvocationError,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -42,6 +118,13 @@ pub struct CniInvocationArguments {
     pub(crate) attachment: Option<CniAttachment>,
     pub(crate) valid_attachments: Option<Vec<CniValidAttachment>>,
     pub(crate) cni_version: Option<CniVersion>,
+    pub(crate) negotiate_cni_version: bool,
+    pub(crate) cni_args: Option<Vec<CniArg>>,
+    pub(crate) capability_args: Option<CniCapabilityArgs>,
+    pub(crate) rollback_on_failure: bool,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) operation_timeouts: HashMap<CniOperation, Duration>,
+    pub(crate) plugin_timeouts: HashMap<String, Duration>,
 }
 
 impl CniInvocationArguments {
@@ -54,6 +137,13 @@ impl CniInvocationArguments {
             attachment: None,
             valid_attachments: None,
             cni_version: None,
+            negotiate_cni_version: false,
+            cni_args: None,
+            capability_args: None,
+            rollback_on_failure: false,
+            timeout: None,
+            operation_timeouts: HashMap::new(),
+            plugin_timeouts: HashMap::new(),
         }
     }
 
@@ -91,6 +181,61 @@ impl CniInvocationArguments {
         self.cni_version = Some(cni_version);
         self
     }
+
+    /// Negotiate the CNI version with the plugins' VERSION responses before every other operation. An explicitly
+    /// provided CNI version takes precedence over negotiation.
+    pub fn negotiate_cni_version(&mut self, negotiate_cni_version: bool) -> &mut Self {
+        self.negotiate_cni_version = negotiate_cni_version;
+        self
+    }
+
+    pub fn cni_args(&mut self, cni_args: Vec<CniArg>) -> &mut Self {
+        self.cni_args = Some(cni_args);
+        self
+    }
+
+    pub fn capability_args(&mut self, capability_args: CniCapabilityArgs) -> &mut Self {
+        self.capability_args = Some(capability_args);
+        self
+    }
+
+    /// When an ADD fails, run DEL in reverse order on the plugins that had already succeeded.
+    pub fn rollback_on_failure(&mut self, rollback_on_failure: bool) -> &mut Self {
+        self.rollback_on_failure = rollback_on_failure;
+        self
+    }
+
+    /// Kill plugins that run longer than this, unless a more specific timeout applies.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Kill plugins that run longer than this during the given operation, unless a plugin timeout applies.
+    pub fn operation_timeout(&mut self, operation: CniOperation, timeout: Duration) -> &mut Self {
+        self.operation_timeouts.insert(operation, timeout);
+        self
+    }
+
+    /// Kill plugins of the given type that run longer than this, taking precedence over all other timeouts.
+    pub fn plugin_timeout(&mut self, plugin_type: impl Into<String>, timeout: Duration) -> &mut Self {
+        self.plugin_timeouts.insert(plugin_type.into(), timeout);
+        self
+    }
+
+    pub(crate) fn timeout_for(&self, operation: CniOperation, plugin_type: &str) -> Option<Duration> {
+        self.plugin_timeouts
+            .get(plugin_type)
+            .or_else(|| self.operation_timeouts.get(&operation))
+            .or(self.timeout.as_ref())
+            .copied()
+    }
+}
+
+impl Default for CniInvocationArguments {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -103,41 +248,360 @@ pub enum CniInvocationTarget<'a> {
     PluginList(&'a CniPluginList),
 }
 
+impl CniInvocationTarget<'_> {
+    pub fn name(&self) -> &CniName {
+        match self {
+            CniInvocationTarget::Plugin { name, .. } => name,
+            CniInvocationTarget::PluginList(plugin_list) => &plugin_list.name,
+        }
+    }
+
+    pub fn plugins(&self) -> &[CniPlugin] {
+        match self {
+            CniInvocationTarget::Plugin { plugin, .. } => std::slice::from_ref(*plugin),
+            CniInvocationTarget::PluginList(plugin_list) => &plugin_list.plugins,
+        }
+    }
+
+    pub fn cni_version(&self) -> &CniVersion {
+        match self {
+            CniInvocationTarget::Plugin { cni_version, .. } => cni_version,
+            CniInvocationTarget::PluginList(plugin_list) => &plugin_list.cni_version,
+        }
+    }
+}
+
+/// Why a locator couldn't resolve a plugin type, along with every candidate path it considered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CniLocatorError {
+    pub plugin_type: String,
+    pub rejected_candidates: Vec<CniRejectedCandidate>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CniRejectedCandidate {
+    pub path: PathBuf,
+    pub reason: CniRejectionReason,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CniRejectionReason {
+    DoesNotExist,
+    NotRegularFile,
+    NotExecutable,
+    Inaccessible(io::ErrorKind),
+    /// The plugin type is empty, `.` or `..`, or contains a `/`, so it could resolve outside of the searched
+    /// directories. No candidates are built for such a plugin type.
+    InvalidPluginType,
+}
+
 #[async_trait]
 pub trait CniLocator {
-    async fn locate(&self, plugin_type: &str) -> Option<PathBuf>;
+    async fn locate(&self, plugin_type: &str) -> Result<PathBuf, CniLocatorError>;
+
+    /// The directories this locator searches, in order. The runtime passes them to plugins as CNI_PATH, so that
+    /// delegated plugins are found the same way. [CniInvocationArguments::paths] is only used for locators that
+    /// don't expose their search paths, and is rejected if it differs from non-empty ones.
+    fn search_paths(&self) -> &[PathBuf] {
+        &[]
+    }
 }
 
+/// A locator with a fixed plugin type to path mapping. The mapped paths are trusted and not checked.
 pub struct MappedCniLocator {
     pub lookup_map: HashMap<String, PathBuf>,
 }
 
 #[async_trait]
 impl CniLocator for MappedCniLocator {
-    async fn locate(&self, plugin_type: &str) -> Option<PathBuf> {
-        self.lookup_map.get(plugin_type).map(|path_buf| path_buf.clone())
+    async fn locate(&self, plugin_type: &str) -> Result<PathBuf, CniLocatorError> {
+        self.lookup_map
+            .get(plugin_type)
+            .cloned()
+            .ok_or_else(|| CniLocatorError {
+                plugin_type: plugin_type.to_owned(),
+                rejected_candidates: Vec::new(),
+            })
     }
 }
 
+/// A locator that searches a single directory for an executable regular file named exactly like the plugin type,
+/// and then for one named like the plugin type followed by each of the suffixes, in order.
 pub struct DirectoryCniLocator {
     pub directory_path: PathBuf,
-    pub exact_name: bool,
+    pub suffixes: Vec<String>,
 }
 
 #[async_trait]
 impl CniLocator for DirectoryCniLocator {
-    async fn locate(&self, plugin_type: &str) -> Option<PathBuf> {
-        let mut read_dir = tokio::fs::read_dir(&self.directory_path).await.ok()?;
-
-        while let Some(entry) = read_dir.next_entry().await.ok()? {
-            if entry.file_name() == plugin_type
-                || (!self.exact_name && entry.file_name().to_string_lossy().contains(plugin_type))
-            {
-                return Some(entry.path());
+    async fn locate(&self, plugin_type: &str) -> Result<PathBuf, CniLocatorError> {
+        validate_plugin_type(plugin_type)?;
+        let candidates = std::iter::once(String::new())
+            .chain(self.suffixes.iter().cloned())
+            .map(|suffix| self.directory_path.join(format!("{plugin_type}{suffix}")));
+        locate_first_executable(plugin_type, candidates).await
+    }
+
+    fn search_paths(&self) -> &[PathBuf] {
+        std::slice::from_ref(&self.directory_path)
+    }
+}
+
+/// A locator that searches an ordered list of directories the way the specification describes CNI_PATH: the first
+/// directory containing an executable regular file named after the plugin type wins.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathCniLocator {
+    pub directory_paths: Vec<PathBuf>,
+}
+
+impl PathCniLocator {
+    pub fn new<P: Into<PathBuf>>(directory_paths: Vec<P>) -> Self {
+        Self {
+            directory_paths: directory_paths.into_iter().map(|path| path.into()).collect(),
+        }
+    }
+
+    /// Search the directories of this process's CNI_PATH, which is empty if the variable isn't set.
+    pub fn from_env() -> Self {
+        Self::from_cni_path(std::env::var_os("CNI_PATH").unwrap_or_default())
+    }
+
+    /// Search the directories of a colon-separated CNI_PATH value, ignoring empty entries.
+    pub fn from_cni_path(cni_path: impl AsRef<OsStr>) -> Self {
+        Self::new(
+            std::env::split_paths(&cni_path)
+                .filter(|path| !path.as_os_str().is_empty())
+                .collect(),
+        )
+    }
+
+    /// Search the same directories that are passed to plugins as CNI_PATH for the given arguments.
+    pub fn from_arguments(arguments: &CniInvocationArguments) -> Self {
+        Self::new(arguments.paths.clone().unwrap_or_default())
+    }
+}
+
+#[async_trait]
+impl CniLocator for PathCniLocator {
+    async fn locate(&self, plugin_type: &str) -> Result<PathBuf, CniLocatorError> {
+        validate_plugin_type(plugin_type)?;
+        let candidates = self
+            .directory_paths
+            .iter()
+            .map(|directory_path| directory_path.join(plugin_type));
+        locate_first_executable(plugin_type, candidates).await
+    }
+
+    fn search_paths(&self) -> &[PathBuf] {
+        &self.directory_paths
+    }
+}
+
+/// A locator that remembers what another locator resolved. The cache is dropped whenever one of the inner locator's
+/// search paths changes, which is detected with inotify. If inotify is unavailable, the inner locator doesn't expose
+/// its search paths or one of them is removed or moved away, entries expire after the given time to live instead.
+/// Failed lookups are never cached.
+pub struct CachingCniLocator<L: CniLocator> {
+    locator: L,
+    time_to_live: Duration,
+    state: Mutex<CachingState>,
+}
+
+struct CachingState {
+    entries: HashMap<String, (PathBuf, Instant)>,
+    inotify: Option<Inotify>,
+}
+
+impl<L: CniLocator> CachingCniLocator<L> {
+    pub fn new(locator: L, time_to_live: Duration) -> Self {
+        let inotify = match locator.search_paths() {
+            [] => None,
+            search_paths => watch_directories(search_paths).ok(),
+        };
+
+        Self {
+            locator,
+            time_to_live,
+            state: Mutex::new(CachingState {
+                entries: HashMap::new(),
+                inotify,
+            }),
+        }
+    }
+
+    pub fn locator(&self) -> &L {
+        &self.locator
+    }
+
+    /// Whether changes are detected with inotify rather than by expiring entries.
+    pub fn is_watching(&self) -> bool {
+        self.lock_state().map(|state| state.inotify.is_some()).unwrap_or(false)
+    }
+
+    pub fn invalidate(&self) {
+        if let Ok(mut state) = self.lock_state() {
+            state.entries.clear();
+        }
+    }
+
+    fn lock_state(&self) -> Result<MutexGuard<'_, CachingState>, io::Error> {
+        self.state
+            .lock()
+            .map_err(|_| io::Error::other("Caching locator mutex was poisoned"))
+    }
+
+    fn cached(&self, plugin_type: &str) -> Option<PathBuf> {
+        let mut state = self.lock_state().ok()?;
+        let state = &mut *state;
+
+        match &mut state.inotify {
+            Some(inotify) => match drain_events(inotify) {
+                Ok(DirectoryChanges::None) => {}
+                Ok(DirectoryChanges::Changed) => state.entries.clear(),
+                // a removed or moved directory isn't watched anymore, even once it is recreated
+                Ok(DirectoryChanges::WatchLost) | Err(_) => {
+                    state.inotify = None;
+                    state.entries.clear();
+                }
+            },
+            None => state
+                .entries
+                .retain(|_, (_, cached_at)| cached_at.elapsed() < self.time_to_live),
+        }
+
+        state.entries.get(plugin_type).map(|(path, _)| path.clone())
+    }
+}
+
+#[async_trait]
+impl<L: CniLocator + Send + Sync> CniLocator for CachingCniLocator<L> {
+    async fn locate(&self, plugin_type: &str) -> Result<PathBuf, CniLocatorError> {
+        if let Some(path) = self.cached(plugin_type) {
+            return Ok(path);
+        }
+
+        let path = self.locator.locate(plugin_type).await?;
+        if let Ok(mut state) = self.lock_state() {
+            state
+                .entries
+                .insert(plugin_type.to_owned(), (path.clone(), Instant::now()));
+        }
+        Ok(path)
+    }
+
+    fn search_paths(&self) -> &[PathBuf] {
+        self.locator.search_paths()
+    }
+}
+
+fn watch_directories(directory_paths: &[PathBuf]) -> Result<Inotify, io::Error> {
+    let inotify = Inotify::init()?;
+    for directory_path in directory_paths {
+        inotify.watches().add(
+            directory_path,
+            WatchMask::CREATE
+                | WatchMask::DELETE
+                | WatchMask::MOVED_FROM
+                | WatchMask::MOVED_TO
+                | WatchMask::ATTRIB
+                | WatchMask::CLOSE_WRITE
+                | WatchMask::DELETE_SELF
+                | WatchMask::MOVE_SELF,
+        )?;
+    }
+    Ok(inotify)
+}
+
+enum DirectoryChanges {
+    None,
+    Changed,
+    WatchLost,
+}
+
+/// Read all pending events without blocking, returning whether there were any and whether a watched directory itself
+/// went away.
+fn drain_events(inotify: &mut Inotify) -> Result<DirectoryChanges, io::Error> {
+    let mut buffer = [0; 4096];
+    let mut changes = DirectoryChanges::None;
+
+    loop {
+        match inotify.read_events(&mut buffer) {
+            Ok(events) => {
+                for event in events {
+                    if event
+                        .mask
+                        .intersects(EventMask::DELETE_SELF | EventMask::MOVE_SELF | EventMask::IGNORED)
+                    {
+                        changes = DirectoryChanges::WatchLost;
+                    } else if !matches!(changes, DirectoryChanges::WatchLost) {
+                        changes = DirectoryChanges::Changed;
+                    }
+                }
             }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(changes),
+            Err(err) => return Err(err),
         }
+    }
+}
+
+/// Reject plugin types that aren't a plain file name, like libcni's FindInPath does.
+fn validate_plugin_type(plugin_type: &str) -> Result<(), CniLocatorError> {
+    if plugin_type.is_empty() || plugin_type == "." || plugin_type == ".." || plugin_type.contains('/') {
+        return Err(CniLocatorError {
+            plugin_type: plugin_type.to_owned(),
+            rejected_candidates: vec![CniRejectedCandidate {
+                path: PathBuf::from(plugin_type),
+                reason: CniRejectionReason::InvalidPluginType,
+            }],
+        });
+    }
+    Ok(())
+}
+
+async fn locate_first_executable(
+    plugin_type: &str,
+    candidates: impl Iterator<Item = PathBuf>,
+) -> Result<PathBuf, CniLocatorError> {
+    let mut rejected_candidates = Vec::new();
+
+    for path in candidates {
+        let reason = match tokio::fs::metadata(&path).await {
+            Ok(metadata) if !metadata.is_file() => CniRejectionReason::NotRegularFile,
+            Ok(metadata) if metadata.permissions().mode() & 0o111 == 0 => CniRejectionReason::NotExecutable,
+            Ok(_) => return Ok(path),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => CniRejectionReason::DoesNotExist,
+            Err(err) => CniRejectionReason::Inaccessible(err.kind()),
+        };
+        rejected_candidates.push(CniRejectedCandidate { path, reason });
+    }
+
+    Err(CniLocatorError {
+        plugin_type: plugin_type.to_owned(),
+        rejected_candidates,
+    })
+}
+
+/// The raw outcome of running a plugin. The exit code is absent if the plugin was terminated by a signal.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CniInvokerOutput {
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CniInvokerOutput {
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+}
 
-        None
+impl From<Output> for CniInvokerOutput {
+    fn from(value: Output) -> Self {
+        Self {
+            exit_code: value.status.code(),
+            stdout: String::from_utf8_lossy(&value.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&value.stderr).into_owned(),
+        }
     }
 }
 
@@ -148,7 +612,7 @@ pub trait CniInvoker {
         program: &Path,
         environment: HashMap<String, String>,
         stdin: String,
-    ) -> Result<String, io::Error>;
+    ) -> Result<CniInvokerOutput, io::Error>;
 }
 
 pub struct RootfulCniInvoker {}
@@ -160,34 +624,21 @@ impl CniInvoker for RootfulCniInvoker {
         program: &Path,
         environment: HashMap<String, String>,
         stdin: String,
-    ) -> Result<String, io::Error> {
-        let mut command = Command::new(program);
-        command
-            .envs(environment)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
-        let mut child = command.spawn()?;
-        let mut child_stdin = child
-            .stdin
-            .take()
-            .ok_or_else(|| io::Error::other("Stdin not found despite having been piped"))?;
-        child_stdin.write_all(stdin.as_bytes()).await?;
-        child_stdin.flush().await?;
-        drop(child_stdin); // EOF
-
-        let output = child.wait_with_output().await?;
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        let stderr = String::from_utf8_lossy(&output.stderr);
-
-        if stdout.len() > stderr.len() {
-            Ok(stdout.into())
-        } else {
-            Ok(stderr.into())
-        }
+    ) -> Result<CniInvokerOutput, io::Error> {
+        let mut command = StdCommand::new(program);
+        command.envs(environment);
+        Ok(run_process(command, stdin.as_bytes()).await?.into())
     }
 }
 
+/// An invoker that escalates privileges with su, writing the password to its stdin. The plugin's stdin and environment
+/// are passed through private temporary files instead of the su session or its world-readable command line, and every
+/// word the shell sees is shell-quoted, so environment values can contain any characters. The shell removes the
+/// temporary files right after reading them, which doubles as reliable authentication detection: if they are still
+/// there once su exits, su never ran the command and [io::ErrorKind::PermissionDenied] is returned.
+///
+/// The plugin runs as root, which an unprivileged process can't signal, so a plugin that times out or whose
+/// invocation is dropped keeps running until it exits on its own.
 pub struct SuCniInvoker {
     pub su_path: PathBuf,
     pub password: String,
@@ -200,40 +651,413 @@ impl CniInvoker for SuCniInvoker {
         program: &Path,
         environment: HashMap<String, String>,
         stdin: String,
-    ) -> Result<String, io::Error> {
-        let mut command = Command::new(self.su_path.as_os_str());
-        command
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
-        let mut child = command.spawn()?;
-        let mut child_stdin = child.stdin.take().ok_or_else(|| io::Error::other("Stdin not found"))?;
-        child_stdin.write_all((self.password.clone() + "\n").as_bytes()).await?;
-
-        let full_command = build_env_string(environment) + program.to_string_lossy().to_string().as_str() + " ; exit\n";
-        child_stdin.write_all(full_command.as_bytes()).await?;
-        child_stdin.write_all(stdin.as_bytes()).await?;
-        drop(child_stdin); // EOF
-
-        let output = child.wait_with_output().await?;
-        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
-        if stderr.contains("fail") {
+    ) -> Result<CniInvokerOutput, io::Error> {
+        let stdin_file = TemporaryFile::create(&stdin).await?;
+        let environment_file = TemporaryFile::create(&environment_script(environment)).await?;
+        let stdin_path = shell_quote(stdin_file.path.as_os_str());
+        let environment_path = shell_quote(environment_file.path.as_os_str());
+        let full_command = format!(
+            "exec 0<{stdin_path} && . {environment_path} && rm -f -- {stdin_path} {environment_path} && exec {}",
+            shell_quote(program.as_os_str())
+        );
+
+        let mut command = StdCommand::new(&self.su_path);
+        command.arg("-c").arg(full_command);
+        let output = run_process(command, format!("{}\n", self.password).as_bytes()).await?;
+
+        if tokio::fs::try_exists(&stdin_file.path).await? {
             return Err(io::Error::new(
                 io::ErrorKind::PermissionDenied,
                 "Authentication was forbidden",
             ));
         }
-        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        Ok(output.into())
+    }
+}
+
+/// An invoker that escalates privileges with sudo. The plugin's stdin is piped through sudo, and sudo never reads a
+/// password from it: authentication either happens through the askpass helper or, in non-interactive mode, fails
+/// immediately. Only the CNI environment is passed (through a private temporary file, never on the command line),
+/// plus the names in the preserved environment allowlist which sudo copies from this process. Failed authentication
+/// is reported as [io::ErrorKind::PermissionDenied].
+///
+/// The plugin runs as root, which an unprivileged process can't signal, so a plugin that times out or whose
+/// invocation is dropped keeps running until it exits on its own.
+pub struct SudoCniInvoker {
+    pub sudo_path: PathBuf,
+    pub non_interactive: bool,
+    pub askpass: Option<PathBuf>,
+    pub preserved_environment: Vec<String>,
+}
+
+#[async_trait]
+impl CniInvoker for SudoCniInvoker {
+    async fn invoke(
+        &self,
+        program: &Path,
+        environment: HashMap<String, String>,
+        stdin: String,
+    ) -> Result<CniInvokerOutput, io::Error> {
+        let mut command = StdCommand::new(&self.sudo_path);
+        if self.non_interactive {
+            command.arg("-n");
+        }
+        if let Some(askpass) = &self.askpass {
+            command.arg("-A").env("SUDO_ASKPASS", askpass);
+        }
+        if !self.preserved_environment.is_empty() {
+            command.arg(format!("--preserve-env={}", self.preserved_environment.join(",")));
+        }
+        command.arg("--");
+
+        invoke_escalated(command, program, environment, stdin).await
+    }
+}
+
+/// An invoker that escalates privileges with pkexec, authenticating through the desktop's polkit agent. pkexec
+/// clears the environment, so the CNI environment is passed through a private temporary file and the plugin's stdin
+/// is piped through. Failed or dismissed authentication is reported as [io::ErrorKind::PermissionDenied].
+///
+/// The plugin runs as root, which an unprivileged process can't signal, so a plugin that times out or whose
+/// invocation is dropped keeps running until it exits on its own.
+pub struct PkexecCniInvoker {
+    pub pkexec_path: PathBuf,
+}
+
+#[async_trait]
+impl CniInvoker for PkexecCniInvoker {
+    async fn invoke(
+        &self,
+        program: &Path,
+        environment: HashMap<String, String>,
+        stdin: String,
+    ) -> Result<CniInvokerOutput, io::Error> {
+        invoke_escalated(StdCommand::new(&self.pkexec_path), program, environment, stdin).await
+    }
+}
+
+/// An invoker for unprivileged environments that runs plugins as root of a user namespace, inside a network
+/// namespace owned by that user namespace. Plugins can then configure interfaces in that network namespace (and in
+/// any network namespace created by them) without real privileges. The namespaces are either created fresh and kept
+/// alive by a holder process for as long as the invoker lives, or taken from persisted paths such as the ones
+/// rootlesskit exposes. Use [RootlessCniInvoker::network_namespace] as the invocation's network namespace.
+pub struct RootlessCniInvoker {
+    user_namespace_path: PathBuf,
+    network_namespace_path: PathBuf,
+    _holder: Option<NamespaceHolder>,
+}
+
+impl RootlessCniInvoker {
+    /// Create a fresh user namespace that maps the current user and group to root, along with a network namespace
+    /// owned by it. Their paths point into /proc of a paused child process, which isn't reaped before the invoker is
+    /// dropped, so its pid can't be reused by another process in the meantime. The paths are invalid afterwards and
+    /// must not be persisted beyond the invoker's lifetime.
+    pub fn new() -> Result<Self, io::Error> {
+        // SAFETY: getuid and getgid have no preconditions and can't fail
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let uid_map = format!("0 {uid} 1");
+        let gid_map = format!("0 {gid} 1");
+
+        let holder = NamespaceHolder::spawn(&uid_map, &gid_map)?;
+        let namespace_directory = PathBuf::from(format!("/proc/{}/ns", holder.pid));
+        Ok(Self {
+            user_namespace_path: namespace_directory.join("user"),
+            network_namespace_path: namespace_directory.join("net"),
+            _holder: Some(holder),
+        })
+    }
+
+    /// Use persisted namespaces, where the network namespace must be owned by the user namespace.
+    pub fn from_paths(user_namespace_path: impl Into<PathBuf>, network_namespace_path: impl Into<PathBuf>) -> Self {
+        Self {
+            user_namespace_path: user_namespace_path.into(),
+            network_namespace_path: network_namespace_path.into(),
+            _holder: None,
+        }
+    }
+
+    pub fn user_namespace_path(&self) -> &Path {
+        &self.user_namespace_path
+    }
+
+    pub fn network_namespace_path(&self) -> &Path {
+        &self.network_namespace_path
+    }
+
+    pub fn network_namespace(&self) -> CniNetworkNamespace {
+        CniNetworkNamespace::LinuxNamespace(self.network_namespace_path.clone())
+    }
+}
+
+/// A forked child that unshared a user and a network namespace and then waits until the write end of its lifetime
+/// pipe is closed, which happens when it is dropped or when this process exits for whatever reason.
+struct NamespaceHolder {
+    pid: libc::pid_t,
+    _lifetime_pipe: OwnedFd,
+}
+
+impl NamespaceHolder {
+    fn spawn(uid_map: &str, gid_map: &str) -> Result<Self, io::Error> {
+        let (status_reader, status_writer) = create_pipe()?;
+        let (lifetime_reader, lifetime_writer) = create_pipe()?;
+
+        // SAFETY: the child only performs raw system calls on data prepared before forking and never returns
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            unsafe {
+                let errno = match unshare_namespaces(uid_map, gid_map) {
+                    Ok(()) => 0,
+                    Err(err) => err.raw_os_error().unwrap_or(libc::EIO),
+                };
+                libc::write(
+                    status_writer.as_raw_fd(),
+                    (&errno as *const i32).cast(),
+                    size_of::<i32>(),
+                );
+                // block until the parent closes its end, retrying reads that a signal interrupted
+                let mut buffer = 0u8;
+                while errno == 0
+                    && libc::read(lifetime_reader.as_raw_fd(), (&mut buffer as *mut u8).cast(), 1) < 0
+                    && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted
+                {}
+                libc::_exit(0);
+            }
+        }
+        if pid < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        drop((status_writer, lifetime_reader));
+        let holder = Self {
+            pid,
+            _lifetime_pipe: lifetime_writer,
+        };
+
+        let mut errno = 0i32;
+        // SAFETY: the buffer is valid for the size of an i32
+        let read = unsafe {
+            libc::read(
+                status_reader.as_raw_fd(),
+                (&mut errno as *mut i32).cast(),
+                size_of::<i32>(),
+            )
+        };
+        match read {
+            read if read != size_of::<i32>() as isize => Err(io::Error::other("Namespace holder exited unexpectedly")),
+            _ if errno != 0 => Err(io::Error::from_raw_os_error(errno)),
+            _ => Ok(holder),
+        }
+    }
+}
+
+impl Drop for NamespaceHolder {
+    fn drop(&mut self) {
+        // SAFETY: the pid belongs to a child of this process that only gets reaped here
+        unsafe {
+            libc::kill(self.pid, libc::SIGKILL);
+            libc::waitpid(self.pid, std::ptr::null_mut(), 0);
+        }
+    }
+}
+
+fn create_pipe() -> Result<(OwnedFd, OwnedFd), io::Error> {
+    let mut pipe = [0; 2];
+    // SAFETY: the array has room for both descriptors, which are owned by nothing else afterwards
+    unsafe {
+        if libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((OwnedFd::from_raw_fd(pipe[0]), OwnedFd::from_raw_fd(pipe[1])))
+    }
+}
+
+/// Move the calling, single-threaded process into a new user and network namespace and become root inside them.
+fn unshare_namespaces(uid_map: &str, gid_map: &str) -> Result<(), io::Error> {
+    // SAFETY: unshare has no memory safety preconditions
+    if unsafe { libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNET) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    write_proc_file(c"/proc/self/setgroups", b"deny")?;
+    write_proc_file(c"/proc/self/uid_map", uid_map.as_bytes())?;
+    write_proc_file(c"/proc/self/gid_map", gid_map.as_bytes())
+}
+
+#[async_trait]
+impl CniInvoker for RootlessCniInvoker {
+    async fn invoke(
+        &self,
+        program: &Path,
+        environment: HashMap<String, String>,
+        stdin: String,
+    ) -> Result<CniInvokerOutput, io::Error> {
+        let user_namespace = tokio::fs::File::open(&self.user_namespace_path).await?;
+        let network_namespace = tokio::fs::File::open(&self.network_namespace_path).await?;
+        let user_namespace_fd = user_namespace.as_raw_fd();
+        let network_namespace_fd = network_namespace.as_raw_fd();
+
+        let mut command = StdCommand::new(program);
+        command.envs(environment);
+        // SAFETY: the closure only performs raw system calls on file descriptors that outlive the spawn
+        unsafe {
+            command.pre_exec(move || {
+                if libc::setns(user_namespace_fd, libc::CLONE_NEWUSER) != 0
+                    || libc::setns(network_namespace_fd, libc::CLONE_NEWNET) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+
+        let output = run_process(command, stdin.as_bytes()).await?;
+        drop((user_namespace, network_namespace));
+        Ok(output.into())
+    }
+}
+
+/// Write to a procfs file from a forked child, which rules out anything that allocates.
+fn write_proc_file(path: &CStr, content: &[u8]) -> Result<(), io::Error> {
+    // SAFETY: the path is NUL-terminated and the content pointer is valid for its length
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let result = match libc::write(fd, content.as_ptr().cast(), content.len()) {
+            written if written < 0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        };
+        libc::close(fd);
+        result
+    }
+}
+
+/// Run the plugin through an escalating command that takes the command to execute as its trailing arguments. The
+/// environment is written to a private temporary file, since the command line of every process is world-readable.
+/// A trampoline shell sources and then removes that file, so a file that still exists afterwards means the
+/// escalating command refused to run anything, regardless of which exit code it used to say so.
+async fn invoke_escalated(
+    mut command: StdCommand,
+    program: &Path,
+    environment: HashMap<String, String>,
+    stdin: String,
+) -> Result<CniInvokerOutput, io::Error> {
+    let environment_file = TemporaryFile::create(&environment_script(environment)).await?;
+
+    command
+        .args(["/bin/sh", "-c", ". \"$0\" && rm -f -- \"$0\" && exec \"$@\""])
+        .arg(&environment_file.path)
+        .arg(program);
+    let output = run_process(command, stdin.as_bytes()).await?;
+
+    if tokio::fs::try_exists(&environment_file.path).await? {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Authentication was forbidden",
+        ));
+    }
+    Ok(output.into())
+}
+
+/// A file only readable by the current user (and root), used to hand over a plugin's stdin or environment, which the
+/// privileged side removes once authentication succeeded. Removed on drop if that didn't already happen.
+struct TemporaryFile {
+    path: PathBuf,
+}
+
+impl TemporaryFile {
+    async fn create(content: &str) -> Result<Self, io::Error> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "tokio-cni-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .await?;
+        let temporary_file = Self { path };
+        file.write_all(content.as_bytes()).await?;
+        file.flush().await?;
+        Ok(temporary_file)
+    }
+}
+
+impl Drop for TemporaryFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Build a script for a POSIX shell that exports the environment, with every value shell-quoted.
+fn environment_script(environment: HashMap<String, String>) -> String {
+    let mut environment = environment.into_iter().collect::<Vec<_>>();
+    environment.sort();
+    environment
+        .into_iter()
+        .map(|(key, value)| format!("export {key}={}\n", shell_quote(value.as_ref())))
+        .collect()
+}
+
+/// Quote a word for a POSIX shell by wrapping it in single quotes, which disable every expansion.
+fn shell_quote(word: &OsStr) -> String {
+    format!("'{}'", word.to_string_lossy().replace('\'', "'\\''"))
+}
+
+/// Run a process in its own process group, write the given stdin to it and collect its output. If the returned
+/// future is dropped before the process exits, for example because a timeout fired, the whole process group is
+/// killed so that no plugin (or anything it spawned) is orphaned. That requires permission to signal the group, which
+/// a process escalated to another user, such as root, doesn't grant.
+async fn run_process(mut command: StdCommand, stdin: &[u8]) -> Result<Output, io::Error> {
+    command.process_group(0);
+    let mut command = Command::from(command);
+    command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    let mut child = command.spawn()?;
+    let process_group_guard = ProcessGroupGuard(child.id());
+
+    let mut child_stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| io::Error::other("Stdin not found despite having been piped"))?;
+    // a process may exit without reading its stdin, such as su or pkexec after failed authentication
+    let written = match child_stdin.write_all(stdin).await {
+        Ok(()) => child_stdin.flush().await,
+        Err(err) => Err(err),
+    };
+    match written {
+        Err(err) if err.kind() != io::ErrorKind::BrokenPipe => return Err(err),
+        _ => drop(child_stdin), // EOF
+    }
+
+    let output = child.wait_with_output().await?;
+    process_group_guard.disarm();
+    Ok(output)
+}
+
+struct ProcessGroupGuard(Option<u32>);
+
+impl ProcessGroupGuard {
+    fn disarm(mut self) {
+        self.0 = None;
     }
 }
 
-fn build_env_string(environment: HashMap<String, String>) -> String {
-    let mut env_string = String::new();
-    for (key, value) in environment {
-        env_string.push_str(&key);
-        env_string.push('=');
-        env_string.push_str(&value);
-        env_string.push(' ');
+impl Drop for ProcessGroupGuard {
+    fn drop(&mut self) {
+        if let Some(process_group) = self.0 {
+            // the result is ignored because nothing can be reported from drop. The one expected failure is EPERM for
+            // a group escalated to another user, which the escalating invokers document as not being killed.
+            // SAFETY: killpg has no memory safety preconditions. The group leader hasn't been reaped, so its id can't
+            // have been reused for another group.
+            let _ = unsafe { libc::killpg(process_group as libc::pid_t, libc::SIGKILL) };
+        }
     }
-    env_string
 }
@@ -1,4 +1,13 @@
+pub mod cache;
+pub mod capabilities;
+pub mod convert;
 pub mod invocation;
+pub mod netns;
 pub mod plugins;
 pub mod runtime;
 pub mod types;
+
+// the fixtures are shared with the integration tests
+#[cfg(test)]
+#[path = "../tests/common/mod.rs"]
+mod testing;
@@ -0,0 +1,241 @@
+use std::{
+    ffi::CString,
+    fs::File,
+    io::ErrorKind,
+    os::{fd::AsRawFd, unix::ffi::OsStrExt},
+    path::{Path, PathBuf},
+};
+
+use tokio::io;
+
+use crate::types::{CniNetnsName, CniNetworkNamespace};
+
+/// The directory iproute2 and most container runtimes keep named network namespaces in.
+pub static DEFAULT_NETNS_DIRECTORY: &str = "/run/netns";
+
+const NSFS_MAGIC: libc::c_long = 0x6e736673;
+const NS_GET_NSTYPE: libc::c_ulong = 0xb703;
+
+#[derive(Debug)]
+pub enum CniNetnsError {
+    IoFailed(io::Error),
+    NotNetworkNamespace(PathBuf),
+}
+
+impl From<io::Error> for CniNetnsError {
+    fn from(value: io::Error) -> Self {
+        CniNetnsError::IoFailed(value)
+    }
+}
+
+/// A network namespace that is kept alive by a bind mount, so that it outlives the processes inside it. Creating and
+/// deleting namespaces requires CAP_SYS_ADMIN, opening existing ones doesn't.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CniNetns {
+    path: PathBuf,
+}
+
+impl CniNetns {
+    /// Create a named network namespace in [DEFAULT_NETNS_DIRECTORY], like `ip netns add` does.
+    pub async fn create(name: &CniNetnsName) -> Result<Self, CniNetnsError> {
+        Self::create_in(DEFAULT_NETNS_DIRECTORY, name).await
+    }
+
+    /// Create a named network namespace in the given directory, which is made a shared mount point first so that the
+    /// namespace also becomes visible in mount namespaces created afterwards.
+    pub async fn create_in(directory_path: impl AsRef<Path>, name: &CniNetnsName) -> Result<Self, CniNetnsError> {
+        let directory_path = directory_path.as_ref().to_owned();
+        let path = directory_path.join(name.as_ref());
+
+        tokio::task::spawn_blocking(move || {
+            std::fs::create_dir_all(&directory_path)?;
+            make_shared_mount_point(&directory_path)?;
+            std::fs::OpenOptions::new().write(true).create_new(true).open(&path)?;
+
+            // unsharing changes the network namespace of the calling thread for good, so use a throwaway thread
+            let bind_path = path.clone();
+            let bind_result = std::thread::spawn(move || -> Result<(), io::Error> {
+                // SAFETY: unshare only affects this thread, which exits right after bind mounting its namespace
+                if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                mount(Path::new("/proc/thread-self/ns/net"), &bind_path, libc::MS_BIND)
+            })
+            .join()
+            .map_err(|_| io::Error::other("Network namespace thread panicked"))?;
+
+            match bind_result {
+                Ok(()) => Ok(Self { path }),
+                Err(err) => {
+                    let _ = std::fs::remove_file(&path);
+                    Err(err.into())
+                }
+            }
+        })
+        .await
+        .map_err(|err| CniNetnsError::IoFailed(io::Error::other(err)))?
+    }
+
+    /// Open an existing network namespace by path, verifying that the path really is one.
+    pub async fn open(path: impl Into<PathBuf>) -> Result<Self, CniNetnsError> {
+        let path = path.into();
+        if !Self::is_network_namespace(&path).await? {
+            return Err(CniNetnsError::NotNetworkNamespace(path));
+        }
+        Ok(Self { path })
+    }
+
+    /// Open a named network namespace in [DEFAULT_NETNS_DIRECTORY].
+    pub async fn open_named(name: &CniNetnsName) -> Result<Self, CniNetnsError> {
+        Self::open(Path::new(DEFAULT_NETNS_DIRECTORY).join(name.as_ref())).await
+    }
+
+    /// Check whether the path is an nsfs file that refers to a network namespace, either a bind mount or a link in
+    /// /proc/[pid]/ns.
+    pub async fn is_network_namespace(path: impl AsRef<Path>) -> Result<bool, io::Error> {
+        let path = path.as_ref().to_owned();
+        tokio::task::spawn_blocking(move || {
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+                Err(err) => return Err(err),
+            };
+
+            // SAFETY: statfs only writes into the zeroed struct, and the descriptor stays open during both calls
+            unsafe {
+                let mut statfs: libc::statfs = std::mem::zeroed();
+                if libc::fstatfs(file.as_raw_fd(), &mut statfs) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if statfs.f_type as libc::c_long != NSFS_MAGIC {
+                    return Ok(false);
+                }
+
+                match libc::ioctl(file.as_raw_fd(), NS_GET_NSTYPE as _) {
+                    namespace_type if namespace_type < 0 => Err(io::Error::last_os_error()),
+                    namespace_type => Ok(namespace_type == libc::CLONE_NEWNET),
+                }
+            }
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Unmount and remove the namespace. The namespace itself is only destroyed by the kernel once no process runs
+    /// inside it anymore.
+    pub async fn delete(self) -> Result<(), CniNetnsError> {
+        tokio::task::spawn_blocking(move || {
+            let path = CString::new(self.path.as_os_str().as_bytes()).map_err(io::Error::from)?;
+            // SAFETY: the path is NUL-terminated
+            if unsafe { libc::umount2(path.as_ptr(), libc::MNT_DETACH) } != 0 {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() != Some(libc::EINVAL) {
+                    return Err(err.into());
+                }
+            }
+            std::fs::remove_file(&self.path)?;
+            Ok(())
+        })
+        .await
+        .map_err(|err| CniNetnsError::IoFailed(io::Error::other(err)))?
+    }
+}
+
+impl From<CniNetns> for CniNetworkNamespace {
+    fn from(value: CniNetns) -> Self {
+        CniNetworkNamespace::LinuxNamespace(value.path)
+    }
+}
+
+impl From<&CniNetns> for CniNetworkNamespace {
+    fn from(value: &CniNetns) -> Self {
+        CniNetworkNamespace::LinuxNamespace(value.path.clone())
+    }
+}
+
+fn make_shared_mount_point(directory_path: &Path) -> Result<(), io::Error> {
+    match mount(Path::new("none"), directory_path, libc::MS_SHARED | libc::MS_REC) {
+        // not a mount point yet, so bind mount the directory onto itself first
+        Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {
+            mount(directory_path, directory_path, libc::MS_BIND | libc::MS_REC)?;
+            mount(Path::new("none"), directory_path, libc::MS_SHARED | libc::MS_REC)
+        }
+        result => result,
+    }
+}
+
+fn mount(source: &Path, target: &Path, flags: libc::c_ulong) -> Result<(), io::Error> {
+    let source = CString::new(source.as_os_str().as_bytes())?;
+    let target = CString::new(target.as_os_str().as_bytes())?;
+    // SAFETY: both paths are NUL-terminated and the file system type and data may be null
+    let result = unsafe {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            std::ptr::null(),
+            flags,
+            std::ptr::null(),
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::fs::MetadataExt, path::PathBuf};
+
+    use crate::{
+        netns::{CniNetns, CniNetnsError},
+        types::{CniNetnsName, CniNetworkNamespace},
+    };
+
+    #[tokio::test]
+    async fn process_namespace_is_network_namespace() {
+        assert!(CniNetns::is_network_namespace("/proc/self/ns/net").await.unwrap());
+        assert!(!CniNetns::is_network_namespace("/proc/self/ns/user").await.unwrap());
+        assert!(!CniNetns::is_network_namespace("/proc/self/status").await.unwrap());
+        assert!(!CniNetns::is_network_namespace("/nonexistent").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn open_rejects_other_files() {
+        assert!(matches!(
+            CniNetns::open("/proc/self/status").await,
+            Err(CniNetnsError::NotNetworkNamespace(_))
+        ));
+        let netns = CniNetns::open("/proc/self/ns/net").await.unwrap();
+        assert_eq!(
+            CniNetworkNamespace::from(netns),
+            CniNetworkNamespace::LinuxNamespace(PathBuf::from("/proc/self/ns/net"))
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "requires root"]
+    async fn create_and_delete_round_trip() {
+        let directory_path = std::env::temp_dir().join(format!("tokio-cni-netns-{}", std::process::id()));
+        let name = CniNetnsName::new("cni-test_ns").unwrap();
+        let netns = CniNetns::create_in(&directory_path, &name).await.unwrap();
+        assert_eq!(netns.path(), directory_path.join("cni-test_ns"));
+        assert!(CniNetns::is_network_namespace(netns.path()).await.unwrap());
+        assert_ne!(
+            std::fs::metadata(netns.path()).unwrap().ino(),
+            std::fs::metadata("/proc/self/ns/net").unwrap().ino()
+        );
+
+        let path = netns.path().to_owned();
+        netns.delete().await.unwrap();
+        assert!(!path.exists());
+
+        let directory = std::ffi::CString::new(directory_path.to_str().unwrap()).unwrap();
+        unsafe { libc::umount2(directory.as_ptr(), libc::MNT_DETACH) };
+        std::fs::remove_dir_all(directory_path).unwrap();
+    }
+}
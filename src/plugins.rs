@@ -1,11 +1,21 @@
-use std::{collections::VecDeque, path::Path};
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
 
 use async_trait::async_trait;
+use inotify::{EventMask, EventStream, Inotify, WatchMask};
 use serde_json::{Map, Value};
 use tokio::{
     fs::{read_to_string, write},
     io,
+    sync::mpsc,
+    task::JoinHandle,
 };
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
 
 use crate::types::{CniName, CniValidationError, CniVersion};
 
@@ -14,9 +24,13 @@ pub struct CniPluginList {
     pub cni_version: CniVersion,
     pub cni_versions: Option<Vec<CniVersion>>,
     pub name: CniName,
-    pub disable_check: bool,
-    pub disable_gc: bool,
+    /// The flags are absent rather than false if the configuration doesn't set them, so that they round-trip.
+    pub disable_check: Option<bool>,
+    pub disable_gc: Option<bool>,
+    pub load_only_inlined_plugins: Option<bool>,
     pub plugins: Vec<CniPlugin>,
+    /// Top-level keys the specification doesn't define, such as "cni.dev/..." extensions, kept for round-trips.
+    pub list_options: Map<String, Value>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -27,16 +41,342 @@ pub struct CniPlugin {
     pub plugin_options: Map<String, Value>,
 }
 
+/// The two on-disk network configuration formats: a single plugin with the network's name and version at the top
+/// level (usually .conf), or a network configuration list (usually .conflist).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CniConfigFormat {
+    Conf,
+    ConfList,
+}
+
+impl CniConfigFormat {
+    /// Determine the format from the file extension. Other extensions, most notably .json, are ambiguous and need
+    /// [CniConfigFormat::detect] on the content.
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Self> {
+        match path.as_ref().extension()?.to_str()? {
+            "conf" => Some(CniConfigFormat::Conf),
+            "conflist" => Some(CniConfigFormat::ConfList),
+            _ => None,
+        }
+    }
+
+    /// Determine the format from the content, which is a list if and only if it has a "plugins" key.
+    pub fn detect(json_value: &Value) -> Self {
+        match json_value.get("plugins") {
+            Some(_) => CniConfigFormat::ConfList,
+            None => CniConfigFormat::Conf,
+        }
+    }
+}
+
+/// The configuration directory containerd and CRI-O read networks from by default.
+pub static DEFAULT_CONFIG_DIRECTORY: &str = "/etc/cni/net.d";
+
+/// The networks found by scanning a configuration directory, along with why the other files in it were skipped.
+#[derive(Debug)]
+pub struct CniConfigDirectory {
+    pub directory_path: PathBuf,
+    pub networks: Vec<CniConfigFile>,
+    pub diagnostics: Vec<CniConfigDiagnostic>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CniConfigFile {
+    pub path: PathBuf,
+    pub plugin_list: CniPluginList,
+}
+
+#[derive(Debug)]
+pub struct CniConfigDiagnostic {
+    pub path: PathBuf,
+    pub error: CniDeserializationError,
+}
+
+impl CniConfigDirectory {
+    /// Load every .conf, .conflist and .json file in the directory in lexicographic order of file names, the way
+    /// containerd and CRI-O do. Files that fail to load are recorded as diagnostics instead of failing the scan.
+    pub async fn scan(directory_path: impl Into<PathBuf>) -> Result<Self, io::Error> {
+        let directory_path = directory_path.into();
+        let mut read_dir = tokio::fs::read_dir(&directory_path).await?;
+        let mut paths = Vec::new();
+
+        while let Some(entry) = read_dir.next_entry().await? {
+            let path = entry.path();
+            let is_config = matches!(
+                path.extension().and_then(|extension| extension.to_str()),
+                Some("conf" | "conflist" | "json")
+            );
+            if is_config {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        let mut networks = Vec::new();
+        let mut diagnostics = Vec::new();
+        for path in paths {
+            // follow symlinks, which is how Kubernetes mounts ConfigMaps, but skip dangling ones
+            match tokio::fs::metadata(&path).await {
+                Ok(metadata) if metadata.is_file() => {}
+                Ok(_) => continue,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => {
+                    let error = CniDeserializationError::FileError(err);
+                    diagnostics.push(CniConfigDiagnostic { path, error });
+                    continue;
+                }
+            }
+
+            match CniPluginList::from_config_file(&path).await {
+                Ok(plugin_list) => networks.push(CniConfigFile { path, plugin_list }),
+                Err(error) => diagnostics.push(CniConfigDiagnostic { path, error }),
+            }
+        }
+
+        Ok(Self {
+            directory_path,
+            networks,
+            diagnostics,
+        })
+    }
+
+    /// Look up a network by name. If several files define it, the first one in lexicographic order wins.
+    pub fn get(&self, name: &CniName) -> Option<&CniPluginList> {
+        self.networks
+            .iter()
+            .map(|network| &network.plugin_list)
+            .find(|plugin_list| &plugin_list.name == name)
+    }
+
+    /// The network of the first valid file, which runtimes use when no network is requested explicitly.
+    pub fn default_network(&self) -> Option<&CniPluginList> {
+        self.networks.first().map(|network| &network.plugin_list)
+    }
+}
+
+/// A change to the networks of a watched configuration directory. Networks are identified by name, so renaming the
+/// file a network is defined in doesn't produce any event.
+#[derive(Debug)]
+pub enum CniConfigEvent {
+    Added(CniPluginList),
+    Modified(CniPluginList),
+    Removed(CniName),
+    Invalid(PathBuf, CniDeserializationError),
+}
+
+/// A stream of [CniConfigEvent]s, which stops watching the directory when dropped.
+pub struct CniConfigWatcher {
+    receiver: ReceiverStream<CniConfigEvent>,
+    task: JoinHandle<()>,
+}
+
+impl Stream for CniConfigWatcher {
+    type Item = CniConfigEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
+impl Drop for CniConfigWatcher {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl CniConfigDirectory {
+    /// Watch a configuration directory with inotify. The stream starts with the current state of the directory,
+    /// reported as additions and invalid files, and then reports every change. Changes are debounced: the directory
+    /// is only rescanned once no file in it has changed for the given duration, so files that are written in several
+    /// steps aren't reported as invalid in between. The stream ends if the directory can no longer be read.
+    pub async fn watch(directory_path: impl Into<PathBuf>, debounce: Duration) -> Result<CniConfigWatcher, io::Error> {
+        let directory_path = directory_path.into();
+        let inotify = Inotify::init()?;
+        inotify.watches().add(
+            &directory_path,
+            WatchMask::CREATE
+                | WatchMask::MODIFY
+                | WatchMask::CLOSE_WRITE
+                | WatchMask::MOVED_FROM
+                | WatchMask::MOVED_TO
+                | WatchMask::DELETE
+                | WatchMask::DELETE_SELF
+                | WatchMask::MOVE_SELF,
+        )?;
+        let events = inotify.into_event_stream([0; 4096])?;
+        let config_directory = Self::scan(&directory_path).await?;
+
+        let (sender, receiver) = mpsc::channel(64);
+        let task = tokio::spawn(watch_config_directory(
+            directory_path,
+            config_directory,
+            events,
+            debounce,
+            sender,
+        ));
+
+        Ok(CniConfigWatcher {
+            receiver: ReceiverStream::new(receiver),
+            task,
+        })
+    }
+}
+
+async fn watch_config_directory(
+    directory_path: PathBuf,
+    config_directory: CniConfigDirectory,
+    mut events: EventStream<[u8; 4096]>,
+    debounce: Duration,
+    sender: mpsc::Sender<CniConfigEvent>,
+) {
+    let mut networks = BTreeMap::new();
+    if send_config_changes(&sender, &mut networks, config_directory, None)
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    while let Some(event) = events.next().await {
+        let mut changed_paths = HashSet::new();
+        let mut overflowed = false;
+        let mut next_event = Some(event);
+
+        while let Some(event) = next_event.take() {
+            match event {
+                Ok(event) if !event.mask.contains(EventMask::Q_OVERFLOW) => {
+                    if let Some(name) = event.name {
+                        changed_paths.insert(directory_path.join(name));
+                    }
+                }
+                _ => overflowed = true,
+            }
+            next_event = tokio::time::timeout(debounce, events.next()).await.ok().flatten();
+        }
+
+        let config_directory = match CniConfigDirectory::scan(directory_path.to_path_buf()).await {
+            Ok(config_directory) => config_directory,
+            Err(_) => {
+                let empty_directory = CniConfigDirectory {
+                    directory_path,
+                    networks: Vec::new(),
+                    diagnostics: Vec::new(),
+                };
+                let _ = send_config_changes(&sender, &mut networks, empty_directory, None).await;
+                return;
+            }
+        };
+        let changed_paths = if overflowed { None } else { Some(&changed_paths) };
+        if send_config_changes(&sender, &mut networks, config_directory, changed_paths)
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Diff a fresh scan against the networks known so far and send the differences. Invalid files are only reported if
+/// they changed, or always if it isn't known which files changed.
+async fn send_config_changes(
+    sender: &mpsc::Sender<CniConfigEvent>,
+    networks: &mut BTreeMap<CniName, CniPluginList>,
+    config_directory: CniConfigDirectory,
+    changed_paths: Option<&HashSet<PathBuf>>,
+) -> Result<(), mpsc::error::SendError<CniConfigEvent>> {
+    let mut events = Vec::new();
+    let mut previous_networks = std::mem::take(networks);
+
+    for network in config_directory.networks {
+        if networks.contains_key(&network.plugin_list.name) {
+            continue;
+        }
+
+        match previous_networks.remove(&network.plugin_list.name) {
+            None => events.push(CniConfigEvent::Added(network.plugin_list.clone())),
+            Some(previous) if previous != network.plugin_list => {
+                events.push(CniConfigEvent::Modified(network.plugin_list.clone()))
+            }
+            Some(_) => {}
+        }
+        networks.insert(network.plugin_list.name.clone(), network.plugin_list);
+    }
+
+    events.extend(previous_networks.into_keys().map(CniConfigEvent::Removed));
+    events.extend(
+        config_directory
+            .diagnostics
+            .into_iter()
+            .filter(|diagnostic| changed_paths.is_none_or(|paths| paths.contains(&diagnostic.path)))
+            .map(|diagnostic| CniConfigEvent::Invalid(diagnostic.path, diagnostic.error)),
+    );
+
+    for event in events {
+        sender.send(event).await?;
+    }
+    Ok(())
+}
+
+/// Errors that occurred at a specific key of the configuration are annotated with its JSON path, for example
+/// "plugins[3].capabilities". The path is empty for the root.
 #[derive(Debug)]
 pub enum CniDeserializationError {
     FileError(io::Error),
     SerdeError(serde_json::Error),
     RootIsNotObject,
-    MissingKey,
-    KeyOfWrongType,
-    EmptyArray,
-    MalformedName(CniValidationError),
-    MalformedVersion(CniValidationError),
+    MissingKey {
+        path: String,
+    },
+    KeyOfWrongType {
+        path: String,
+        expected: CniJsonType,
+        actual: CniJsonType,
+    },
+    EmptyArray {
+        path: String,
+    },
+    MalformedName {
+        path: String,
+        error: CniValidationError,
+    },
+    MalformedVersion {
+        path: String,
+        error: CniValidationError,
+    },
+    /// Several problems were found in one pass, top-level keys first and then the plugins by index.
+    Multiple(Vec<CniDeserializationError>),
+}
+
+impl CniDeserializationError {
+    fn from_errors(mut errors: Vec<CniDeserializationError>) -> Self {
+        match errors.len() {
+            1 => errors.remove(0),
+            _ => CniDeserializationError::Multiple(errors),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CniJsonType {
+    Null,
+    Bool,
+    Number,
+    String,
+    Array,
+    Object,
+}
+
+impl CniJsonType {
+    pub fn of(json_value: &Value) -> Self {
+        match json_value {
+            Value::Null => CniJsonType::Null,
+            Value::Bool(_) => CniJsonType::Bool,
+            Value::Number(_) => CniJsonType::Number,
+            Value::String(_) => CniJsonType::String,
+            Value::Array(_) => CniJsonType::Array,
+            Value::Object(_) => CniJsonType::Object,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -44,6 +384,9 @@ pub enum CniSerializationError {
     FileError(io::Error),
     SerdeError(serde_json::Error),
     OverlappingKey,
+    /// The list can't be written as a single plugin configuration: it doesn't have exactly one plugin or it sets
+    /// list-only keys.
+    NotSinglePlugin,
 }
 
 #[async_trait]
@@ -54,8 +397,7 @@ pub trait CniDeserializable: Sized {
     }
 
     fn from_string(content: impl AsRef<str>) -> Result<Self, CniDeserializationError> {
-        let json_value: Value =
-            serde_json::from_str(content.as_ref()).map_err(|err| CniDeserializationError::SerdeError(err))?;
+        let json_value: Value = serde_json::from_str(content.as_ref()).map_err(CniDeserializationError::SerdeError)?;
         Self::from_json_value(json_value)
     }
 
@@ -71,126 +413,301 @@ pub trait CniSerializable: Sized {
 
     fn to_string(self) -> Result<String, CniSerializationError> {
         let json_value = self.to_json_value()?;
-        serde_json::to_string(&json_value).map_err(|err| CniSerializationError::SerdeError(err))
+        serde_json::to_string(&json_value).map_err(CniSerializationError::SerdeError)
     }
 
     fn to_json_value(self) -> Result<Value, CniSerializationError>;
 }
 
+impl CniPluginList {
+    /// Load a network configuration file in either format, choosing the format by extension and falling back to
+    /// looking at the content. A single plugin configuration becomes a one-element list.
+    pub async fn from_config_file(path: impl AsRef<Path> + Send) -> Result<Self, CniDeserializationError> {
+        let content = read_to_string(path.as_ref())
+            .await
+            .map_err(CniDeserializationError::FileError)?;
+        let json_value: Value = serde_json::from_str(&content).map_err(CniDeserializationError::SerdeError)?;
+        let format = CniConfigFormat::from_path(path).unwrap_or_else(|| CniConfigFormat::detect(&json_value));
+        Self::from_config_json_value(json_value, format)
+    }
+
+    pub fn from_config_json_value(json_value: Value, format: CniConfigFormat) -> Result<Self, CniDeserializationError> {
+        match format {
+            CniConfigFormat::Conf => Self::from_conf_json_value(json_value),
+            CniConfigFormat::ConfList => Self::from_json_value(json_value),
+        }
+    }
+
+    /// Convert a single plugin configuration into a one-element list, like libcni's ConfListFromConf. The network's
+    /// name and version move from the plugin to the list.
+    pub fn from_conf_json_value(json_value: Value) -> Result<Self, CniDeserializationError> {
+        let mut obj = match json_value {
+            Value::Object(obj) => obj,
+            _ => return Err(CniDeserializationError::RootIsNotObject),
+        };
+        let mut errors = Vec::new();
+
+        let cni_version = parse_version(obj.remove("cniVersion"), "cniVersion", &mut errors);
+        let name = parse_name(obj.remove("name"), "name", &mut errors);
+        let plugin = parse_plugin(Value::Object(obj), "", &mut errors);
+
+        match (cni_version, name, plugin) {
+            (Some(cni_version), Some(name), Some(plugin)) if errors.is_empty() => Ok(CniPluginList {
+                cni_version,
+                cni_versions: None,
+                name,
+                disable_check: None,
+                disable_gc: None,
+                load_only_inlined_plugins: None,
+                plugins: vec![plugin],
+                list_options: Map::new(),
+            }),
+            _ => Err(CniDeserializationError::from_errors(errors)),
+        }
+    }
+
+    /// Write a network configuration file in the format its extension implies, so that a loaded .conf can be
+    /// written back to its own path. Other extensions get a network configuration list.
+    pub async fn to_config_file(self, path: impl AsRef<Path> + Send) -> Result<(), CniSerializationError> {
+        let format = CniConfigFormat::from_path(path.as_ref()).unwrap_or(CniConfigFormat::ConfList);
+        let json_value = self.to_config_json_value(format)?;
+        let content = serde_json::to_string(&json_value).map_err(CniSerializationError::SerdeError)?;
+        write(path, content).await.map_err(CniSerializationError::FileError)
+    }
+
+    pub fn to_config_json_value(self, format: CniConfigFormat) -> Result<Value, CniSerializationError> {
+        match format {
+            CniConfigFormat::Conf => self.to_conf_json_value(),
+            CniConfigFormat::ConfList => self.to_json_value(),
+        }
+    }
+
+    /// The inverse of [CniPluginList::from_conf_json_value]: a one-element list becomes a single plugin
+    /// configuration with the network's name and version at the top level.
+    pub fn to_conf_json_value(mut self) -> Result<Value, CniSerializationError> {
+        let is_single_plugin = self.plugins.len() == 1
+            && self.cni_versions.is_none()
+            && self.disable_check.is_none()
+            && self.disable_gc.is_none()
+            && self.load_only_inlined_plugins.is_none()
+            && self.list_options.is_empty();
+        if !is_single_plugin {
+            return Err(CniSerializationError::NotSinglePlugin);
+        }
+
+        let mut map = match self.plugins.remove(0).to_json_value()? {
+            Value::Object(map) => map,
+            _ => unreachable!("plugins serialize to objects"),
+        };
+        if map.contains_key("cniVersion") || map.contains_key("name") {
+            return Err(CniSerializationError::OverlappingKey);
+        }
+        map.insert("cniVersion".into(), Value::String(self.cni_version.into()));
+        map.insert("name".into(), Value::String(self.name.into()));
+
+        Ok(Value::Object(map))
+    }
+}
+
 impl CniDeserializable for CniPluginList {
-    fn from_json_value(mut json_value: Value) -> Result<Self, CniDeserializationError> {
-        let obj = json_value
-            .as_object_mut()
-            .ok_or(CniDeserializationError::RootIsNotObject)?;
-        let cni_version: CniVersion = CniVersion::parse(
-            obj.remove("cniVersion")
-                .ok_or(CniDeserializationError::MissingKey)?
-                .as_str()
-                .ok_or(CniDeserializationError::KeyOfWrongType)?,
-        )
-        .map_err(CniDeserializationError::MalformedVersion)?;
+    /// Deserialize a network configuration list, reporting every problem at once instead of only the first one.
+    fn from_json_value(json_value: Value) -> Result<Self, CniDeserializationError> {
+        let mut obj = match json_value {
+            Value::Object(obj) => obj,
+            _ => return Err(CniDeserializationError::RootIsNotObject),
+        };
+        let mut errors = Vec::new();
+
+        let cni_version = parse_version(obj.remove("cniVersion"), "cniVersion", &mut errors);
         let cni_versions = match obj.remove("cniVersions") {
-            Some(list) => {
-                let list = list.as_array().ok_or(CniDeserializationError::KeyOfWrongType)?;
-                let mut parsed_list: Vec<CniVersion> = Vec::with_capacity(list.len());
-                for list_value in list {
-                    parsed_list.push(
-                        CniVersion::parse(list_value.as_str().ok_or(CniDeserializationError::KeyOfWrongType)?)
-                            .map_err(CniDeserializationError::MalformedVersion)?,
-                    );
-                }
-                if parsed_list.is_empty() {
-                    return Err(CniDeserializationError::EmptyArray);
-                }
-                Some(parsed_list)
-            }
+            Some(value) => expect_non_empty_array(value, "cniVersions", &mut errors).map(|list| {
+                list.into_iter()
+                    .enumerate()
+                    .filter_map(|(index, value)| {
+                        parse_version(Some(value), &format!("cniVersions[{index}]"), &mut errors)
+                    })
+                    .collect::<Vec<_>>()
+            }),
             None => None,
         };
+        let name = parse_name(obj.remove("name"), "name", &mut errors);
 
-        let name = CniName::new(
-            obj.remove("name")
-                .ok_or(CniDeserializationError::MissingKey)?
-                .as_str()
-                .ok_or(CniDeserializationError::KeyOfWrongType)?,
-        )
-        .map_err(CniDeserializationError::MalformedName)?;
+        let mut parse_flag = |key: &str| obj.remove(key).and_then(|value| expect_bool(value, key, &mut errors));
+        let disable_check = parse_flag("disableCheck");
+        let disable_gc = parse_flag("disableGC");
+        let load_only_inlined_plugins = parse_flag("loadOnlyInlinedPlugins");
 
-        let disable_check = match obj.remove("disableCheck") {
-            Some(val) => val.as_bool().ok_or(CniDeserializationError::KeyOfWrongType)?,
-            None => false,
-        };
-        let disable_gc = match obj.remove("disableGC") {
-            Some(val) => val.as_bool().ok_or(CniDeserializationError::KeyOfWrongType)?,
-            None => false,
+        let plugins = match obj.remove("plugins") {
+            Some(value) => expect_non_empty_array(value, "plugins", &mut errors).map(|list| {
+                list.into_iter()
+                    .enumerate()
+                    .filter_map(|(index, value)| parse_plugin(value, &format!("plugins[{index}]"), &mut errors))
+                    .collect::<Vec<_>>()
+            }),
+            None => {
+                errors.push(CniDeserializationError::MissingKey { path: "plugins".into() });
+                None
+            }
         };
 
-        let plugin_jsons = obj.remove("plugins").ok_or(CniDeserializationError::MissingKey)?;
-        let mut plugin_jsons = match plugin_jsons {
-            Value::Array(array) => VecDeque::from(array),
-            _ => return Err(CniDeserializationError::KeyOfWrongType),
-        };
-        if plugin_jsons.is_empty() {
-            return Err(CniDeserializationError::EmptyArray);
+        match (cni_version, name, plugins) {
+            (Some(cni_version), Some(name), Some(plugins)) if errors.is_empty() => Ok(CniPluginList {
+                cni_version,
+                cni_versions,
+                name,
+                disable_check,
+                disable_gc,
+                load_only_inlined_plugins,
+                plugins,
+                list_options: obj,
+            }),
+            _ => Err(CniDeserializationError::from_errors(errors)),
         }
+    }
+}
 
-        let mut plugins: Vec<CniPlugin> = Vec::with_capacity(plugin_jsons.len());
-        while let Some(plugin_json) = plugin_jsons.pop_front() {
-            plugins.push(CniPlugin::from_json_value(plugin_json)?);
+impl CniDeserializable for CniPlugin {
+    fn from_json_value(json_value: Value) -> Result<Self, CniDeserializationError> {
+        if !json_value.is_object() {
+            return Err(CniDeserializationError::RootIsNotObject);
         }
 
-        Ok(CniPluginList {
-            cni_version,
-            cni_versions,
-            name,
-            disable_check,
-            disable_gc,
-            plugins,
-        })
+        let mut errors = Vec::new();
+        match parse_plugin(json_value, "", &mut errors) {
+            Some(plugin) if errors.is_empty() => Ok(plugin),
+            _ => Err(CniDeserializationError::from_errors(errors)),
+        }
     }
 }
 
-impl CniDeserializable for CniPlugin {
-    fn from_json_value(json_value: Value) -> Result<Self, CniDeserializationError> {
-        let obj = match json_value {
-            Value::Object(x) => x,
-            _ => return Err(CniDeserializationError::KeyOfWrongType),
-        };
+fn parse_plugin(json_value: Value, path: &str, errors: &mut Vec<CniDeserializationError>) -> Option<CniPlugin> {
+    let obj = expect_object(json_value, path, errors)?;
+    let previous_error_count = errors.len();
 
-        let mut plugin_type_option: Option<String> = None;
-        let mut args: Option<Map<String, Value>> = None;
-        let mut capabilities: Option<Map<String, Value>> = None;
-        let mut plugin_options: Map<String, Value> = Map::new();
+    let mut plugin_type_option: Option<Value> = None;
+    let mut args: Option<Map<String, Value>> = None;
+    let mut capabilities: Option<Map<String, Value>> = None;
+    let mut plugin_options: Map<String, Value> = Map::new();
 
-        for (key, value) in obj.into_iter() {
-            match key.as_str() {
-                "type" => {
-                    plugin_type_option = Some(value.as_str().ok_or(CniDeserializationError::KeyOfWrongType)?.into());
-                }
-                "args" => {
-                    args = Some(match value {
-                        Value::Object(x) => x,
-                        _ => return Err(CniDeserializationError::KeyOfWrongType),
-                    })
-                }
-                "capabilities" => {
-                    capabilities = Some(match value {
-                        Value::Object(x) => x,
-                        _ => return Err(CniDeserializationError::KeyOfWrongType),
-                    });
-                }
-                _ => {
-                    plugin_options.insert(key, value);
-                }
+    for (key, value) in obj.into_iter() {
+        match key.as_str() {
+            "type" => plugin_type_option = Some(value),
+            "args" => args = expect_object(value, &join_path(path, &key), errors),
+            "capabilities" => capabilities = expect_object(value, &join_path(path, &key), errors),
+            _ => {
+                plugin_options.insert(key, value);
             }
         }
+    }
+
+    let type_path = join_path(path, "type");
+    let plugin_type = expect_key(plugin_type_option, &type_path, errors)
+        .and_then(|plugin_type| expect_string(plugin_type, &type_path, errors));
+    if errors.len() > previous_error_count {
+        return None;
+    }
 
-        let plugin_type = plugin_type_option.ok_or(CniDeserializationError::MissingKey)?;
-        Ok(CniPlugin {
-            plugin_type,
-            args,
-            capabilities,
-            plugin_options,
+    Some(CniPlugin {
+        plugin_type: plugin_type?,
+        args,
+        capabilities,
+        plugin_options,
+    })
+}
+
+fn parse_version(
+    json_value: Option<Value>,
+    path: &str,
+    errors: &mut Vec<CniDeserializationError>,
+) -> Option<CniVersion> {
+    let version = expect_string(expect_key(json_value, path, errors)?, path, errors)?;
+    CniVersion::parse(version)
+        .map_err(|error| {
+            errors.push(CniDeserializationError::MalformedVersion {
+                path: path.into(),
+                error,
+            })
         })
+        .ok()
+}
+
+fn parse_name(json_value: Option<Value>, path: &str, errors: &mut Vec<CniDeserializationError>) -> Option<CniName> {
+    let name = expect_string(expect_key(json_value, path, errors)?, path, errors)?;
+    CniName::new(name)
+        .map_err(|error| {
+            errors.push(CniDeserializationError::MalformedName {
+                path: path.into(),
+                error,
+            })
+        })
+        .ok()
+}
+
+fn expect_key(json_value: Option<Value>, path: &str, errors: &mut Vec<CniDeserializationError>) -> Option<Value> {
+    if json_value.is_none() {
+        errors.push(CniDeserializationError::MissingKey { path: path.into() });
+    }
+    json_value
+}
+
+fn expect_string(json_value: Value, path: &str, errors: &mut Vec<CniDeserializationError>) -> Option<String> {
+    match json_value {
+        Value::String(string) => Some(string),
+        other => wrong_type(&other, CniJsonType::String, path, errors),
+    }
+}
+
+fn expect_bool(json_value: Value, path: &str, errors: &mut Vec<CniDeserializationError>) -> Option<bool> {
+    match json_value {
+        Value::Bool(bool) => Some(bool),
+        other => wrong_type(&other, CniJsonType::Bool, path, errors),
+    }
+}
+
+fn expect_object(
+    json_value: Value,
+    path: &str,
+    errors: &mut Vec<CniDeserializationError>,
+) -> Option<Map<String, Value>> {
+    match json_value {
+        Value::Object(obj) => Some(obj),
+        other => wrong_type(&other, CniJsonType::Object, path, errors),
+    }
+}
+
+fn expect_non_empty_array(
+    json_value: Value,
+    path: &str,
+    errors: &mut Vec<CniDeserializationError>,
+) -> Option<Vec<Value>> {
+    match json_value {
+        Value::Array(array) if array.is_empty() => {
+            errors.push(CniDeserializationError::EmptyArray { path: path.into() });
+            None
+        }
+        Value::Array(array) => Some(array),
+        other => wrong_type(&other, CniJsonType::Array, path, errors),
+    }
+}
+
+fn wrong_type<T>(
+    json_value: &Value,
+    expected: CniJsonType,
+    path: &str,
+    errors: &mut Vec<CniDeserializationError>,
+) -> Option<T> {
+    errors.push(CniDeserializationError::KeyOfWrongType {
+        path: path.into(),
+        expected,
+        actual: CniJsonType::of(json_value),
+    });
+    None
+}
+
+fn join_path(path: &str, key: &str) -> String {
+    match path {
+        "" => key.into(),
+        _ => format!("{path}.{key}"),
     }
 }
 
@@ -207,8 +724,16 @@ impl CniSerializable for CniPluginList {
         }
 
         map.insert("name".into(), Value::String(self.name.into()));
-        map.insert("disableCheck".into(), Value::Bool(self.disable_check));
-        map.insert("disableGC".into(), Value::Bool(self.disable_gc));
+
+        if let Some(disable_check) = self.disable_check {
+            map.insert("disableCheck".into(), Value::Bool(disable_check));
+        }
+        if let Some(disable_gc) = self.disable_gc {
+            map.insert("disableGC".into(), Value::Bool(disable_gc));
+        }
+        if let Some(load_only_inlined_plugins) = self.load_only_inlined_plugins {
+            map.insert("loadOnlyInlinedPlugins".into(), Value::Bool(load_only_inlined_plugins));
+        }
 
         let mut plugins: Vec<Value> = Vec::with_capacity(self.plugins.len());
         for plugin in self.plugins.into_iter() {
@@ -216,6 +741,15 @@ impl CniSerializable for CniPluginList {
         }
         map.insert("plugins".into(), Value::Array(plugins));
 
+        for (key, value) in self.list_options {
+            if map.contains_key(&key) || matches!(key.as_str(), "disableCheck" | "disableGC" | "loadOnlyInlinedPlugins")
+            {
+                return Err(CniSerializationError::OverlappingKey);
+            }
+
+            map.insert(key, value);
+        }
+
         Ok(Value::Object(map))
     }
 }
@@ -243,3 +777,342 @@ impl CniSerializable for CniPlugin {
         Ok(Value::Object(map))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, time::Duration};
+
+    use serde_json::json;
+    use tokio_stream::StreamExt;
+
+    use crate::{
+        plugins::{
+            CniConfigDirectory, CniConfigEvent, CniConfigFormat, CniConfigWatcher, CniDeserializable,
+            CniDeserializationError, CniJsonType, CniPlugin, CniPluginList, CniSerializable, CniSerializationError,
+        },
+        testing::TemporaryDirectory,
+        types::CniName,
+    };
+
+    const CONF: &str = r#"{
+        "cniVersion": "1.0.0",
+        "name": "testnet",
+        "type": "bridge",
+        "bridge": "cni0",
+        "ipam": { "type": "host-local", "subnet": "10.0.0.0/24" }
+    }"#;
+
+    #[test]
+    fn format_is_detected_by_extension_and_content() {
+        assert_eq!(CniConfigFormat::from_path("10-net.conf"), Some(CniConfigFormat::Conf));
+        assert_eq!(
+            CniConfigFormat::from_path("10-net.conflist"),
+            Some(CniConfigFormat::ConfList)
+        );
+        assert_eq!(CniConfigFormat::from_path("10-net.json"), None);
+        assert_eq!(
+            CniConfigFormat::detect(&json!({ "plugins": [] })),
+            CniConfigFormat::ConfList
+        );
+        assert_eq!(
+            CniConfigFormat::detect(&json!({ "type": "bridge" })),
+            CniConfigFormat::Conf
+        );
+    }
+
+    #[test]
+    fn conf_becomes_single_plugin_list() {
+        let plugin_list = CniPluginList::from_conf_json_value(serde_json::from_str(CONF).unwrap()).unwrap();
+        assert_eq!(plugin_list.name.as_ref(), "testnet");
+        assert_eq!(plugin_list.cni_version.to_string(), "1.0.0");
+        assert_eq!(plugin_list.plugins.len(), 1);
+
+        let plugin = &plugin_list.plugins[0];
+        assert_eq!(plugin.plugin_type, "bridge");
+        assert_eq!(plugin.plugin_options.get("bridge"), Some(&json!("cni0")));
+        assert!(!plugin.plugin_options.contains_key("name"));
+        assert!(!plugin.plugin_options.contains_key("cniVersion"));
+
+        let round_tripped = CniPluginList::from_string(plugin_list.clone().to_string().unwrap()).unwrap();
+        assert_eq!(round_tripped, plugin_list);
+    }
+
+    #[tokio::test]
+    async fn config_files_load_in_both_formats() {
+        let directory_path = TemporaryDirectory::new("config-files");
+        let conf_path = directory_path.join("net.conf");
+        let json_path = directory_path.join("net.json");
+        std::fs::write(&conf_path, CONF).unwrap();
+
+        let plugin_list = CniPluginList::from_config_file(&conf_path).await.unwrap();
+        plugin_list.clone().to_file(&json_path).await.unwrap();
+        assert_eq!(CniPluginList::from_config_file(&json_path).await.unwrap(), plugin_list);
+
+        plugin_list.clone().to_config_file(&conf_path).await.unwrap();
+        assert!(!std::fs::read_to_string(&conf_path).unwrap().contains("plugins"));
+        assert_eq!(CniPluginList::from_config_file(&conf_path).await.unwrap(), plugin_list);
+
+        let mut two_plugins = plugin_list.clone();
+        two_plugins.plugins.push(plugin_list.plugins[0].clone());
+        assert!(matches!(
+            two_plugins.clone().to_config_file(&conf_path).await,
+            Err(CniSerializationError::NotSinglePlugin)
+        ));
+        two_plugins
+            .to_config_file(directory_path.join("net.conflist"))
+            .await
+            .unwrap();
+
+        std::fs::write(&json_path, CONF).unwrap();
+        assert_eq!(CniPluginList::from_config_file(&json_path).await.unwrap(), plugin_list);
+    }
+
+    #[tokio::test]
+    async fn config_directory_scan_orders_and_diagnoses() {
+        let directory_path = TemporaryDirectory::new("net.d");
+        std::fs::create_dir_all(directory_path.join("99-directory.conf")).unwrap();
+        std::fs::write(directory_path.join("10-broken.conflist"), "{ not json").unwrap();
+        std::fs::write(directory_path.join("20-bridge.conf"), CONF).unwrap();
+        std::fs::write(
+            directory_path.join("30-other.json"),
+            r#"{ "cniVersion": "1.0.0", "name": "other", "plugins": [{ "type": "ptp" }] }"#,
+        )
+        .unwrap();
+        std::fs::write(directory_path.join("05-ignored.txt"), CONF).unwrap();
+        std::fs::write(
+            directory_path.join("40-shadowed.conf"),
+            CONF.replace("bridge\",", "macvlan\","),
+        )
+        .unwrap();
+        // the layout of a mounted Kubernetes ConfigMap, plus a dangling link
+        std::fs::create_dir_all(directory_path.join("..data")).unwrap();
+        std::fs::write(
+            directory_path.join("..data").join("50-configmap.conflist"),
+            r#"{ "cniVersion": "1.0.0", "name": "configmap", "plugins": [{ "type": "ptp" }] }"#,
+        )
+        .unwrap();
+        std::os::unix::fs::symlink(
+            "..data/50-configmap.conflist",
+            directory_path.join("50-configmap.conflist"),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink("missing.conf", directory_path.join("60-dangling.conf")).unwrap();
+        std::os::unix::fs::symlink("70-loop.conf", directory_path.join("70-loop.conf")).unwrap();
+
+        let config_directory = CniConfigDirectory::scan(directory_path.to_path_buf()).await.unwrap();
+        let file_names = config_directory
+            .networks
+            .iter()
+            .map(|network| network.path.file_name().unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            file_names,
+            [
+                "20-bridge.conf",
+                "30-other.json",
+                "40-shadowed.conf",
+                "50-configmap.conflist"
+            ]
+        );
+
+        assert_eq!(config_directory.diagnostics.len(), 2);
+        assert_eq!(
+            config_directory.diagnostics[0].path,
+            directory_path.join("10-broken.conflist")
+        );
+        assert!(matches!(
+            config_directory.diagnostics[0].error,
+            CniDeserializationError::SerdeError(_)
+        ));
+        // a symlink loop is reported instead of aborting the scan
+        assert_eq!(
+            config_directory.diagnostics[1].path,
+            directory_path.join("70-loop.conf")
+        );
+        assert!(matches!(
+            &config_directory.diagnostics[1].error,
+            CniDeserializationError::FileError(err) if err.raw_os_error() == Some(libc::ELOOP)
+        ));
+
+        let default_network = config_directory.default_network().unwrap();
+        assert_eq!(default_network.name.as_ref(), "testnet");
+        assert_eq!(
+            config_directory.get(&CniName::new("testnet").unwrap()),
+            Some(default_network)
+        );
+        assert_eq!(default_network.plugins[0].plugin_type, "bridge");
+        assert_eq!(
+            config_directory.get(&CniName::new("other").unwrap()).unwrap().plugins[0].plugin_type,
+            "ptp"
+        );
+        assert_eq!(config_directory.get(&CniName::new("missing").unwrap()), None);
+    }
+
+    async fn next_event(watcher: &mut CniConfigWatcher) -> CniConfigEvent {
+        tokio::time::timeout(Duration::from_secs(5), watcher.next())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn config_directory_watch_streams_changes() {
+        let directory_path = TemporaryDirectory::new("watched-net.d");
+        std::fs::write(directory_path.join("10-bridge.conf"), CONF).unwrap();
+
+        let mut watcher = CniConfigDirectory::watch(directory_path.to_path_buf(), Duration::from_millis(200))
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_event(&mut watcher).await,
+            CniConfigEvent::Added(plugin_list) if plugin_list.name.as_ref() == "testnet"
+        ));
+
+        // a file written in several steps is only parsed once it's complete
+        let other = r#"{ "cniVersion": "1.0.0", "name": "other", "plugins": [{ "type": "ptp" }] }"#;
+        let (first_half, second_half) = other.split_at(other.len() / 2);
+        let mut file = std::fs::File::create(directory_path.join("20-other.conflist")).unwrap();
+        file.write_all(first_half.as_bytes()).unwrap();
+        file.flush().unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        file.write_all(second_half.as_bytes()).unwrap();
+        drop(file);
+        assert!(matches!(
+            next_event(&mut watcher).await,
+            CniConfigEvent::Added(plugin_list) if plugin_list.name.as_ref() == "other"
+        ));
+
+        std::fs::write(directory_path.join("10-bridge.conf"), CONF.replace("cni0", "cni1")).unwrap();
+        assert!(matches!(
+            next_event(&mut watcher).await,
+            CniConfigEvent::Modified(plugin_list) if plugin_list.plugins[0].plugin_options["bridge"] == "cni1"
+        ));
+
+        std::fs::write(directory_path.join("30-broken.json"), "{").unwrap();
+        assert!(matches!(
+            next_event(&mut watcher).await,
+            CniConfigEvent::Invalid(path, CniDeserializationError::SerdeError(_))
+                if path == directory_path.join("30-broken.json")
+        ));
+
+        std::fs::remove_file(directory_path.join("20-other.conflist")).unwrap();
+        assert!(matches!(
+            next_event(&mut watcher).await,
+            CniConfigEvent::Removed(name) if name == CniName::new("other").unwrap()
+        ));
+
+        std::fs::remove_dir_all(&directory_path).unwrap();
+        assert!(matches!(
+            next_event(&mut watcher).await,
+            CniConfigEvent::Removed(name) if name == CniName::new("testnet").unwrap()
+        ));
+        assert!(tokio::time::timeout(Duration::from_secs(5), watcher.next())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn plugin_list_round_trips_losslessly() {
+        let json_value = json!({
+            "cniVersion": "1.1.0",
+            "cniVersions": ["1.0.0", "1.1.0"],
+            "name": "testnet",
+            "disableCheck": false,
+            "disableGC": true,
+            "loadOnlyInlinedPlugins": true,
+            "cni.dev/extension": { "enabled": true },
+            "plugins": [
+                { "type": "bridge", "bridge": "cni0", "capabilities": { "ips": true } },
+                { "type": "firewall", "args": { "cni": { "allow": true } } }
+            ]
+        });
+
+        let plugin_list = CniPluginList::from_json_value(json_value.clone()).unwrap();
+        assert_eq!(plugin_list.load_only_inlined_plugins, Some(true));
+        assert_eq!(plugin_list.disable_gc, Some(true));
+        assert_eq!(plugin_list.disable_check, Some(false));
+        assert_eq!(
+            plugin_list.list_options.get("cni.dev/extension"),
+            Some(&json!({ "enabled": true }))
+        );
+        assert_eq!(plugin_list.to_json_value().unwrap(), json_value);
+    }
+
+    #[test]
+    fn plugin_list_options_cannot_overlap_known_keys() {
+        let mut plugin_list = CniPluginList::from_conf_json_value(serde_json::from_str(CONF).unwrap()).unwrap();
+        plugin_list.list_options.insert("disableCheck".into(), json!(false));
+        assert!(matches!(
+            plugin_list.to_json_value(),
+            Err(CniSerializationError::OverlappingKey)
+        ));
+    }
+
+    #[test]
+    fn deserialization_errors_carry_paths_and_are_collected() {
+        let json_value = json!({
+            "cniVersion": "1.0.0",
+            "cniVersions": ["1.0.0", "one"],
+            "name": 5,
+            "disableCheck": "yes",
+            "plugins": [
+                { "type": "bridge" },
+                { "bridge": "cni0" },
+                { "type": "firewall" },
+                { "type": "portmap", "capabilities": ["portMappings"] },
+                "tuning"
+            ]
+        });
+
+        let errors = match CniPluginList::from_json_value(json_value) {
+            Err(CniDeserializationError::Multiple(errors)) => errors,
+            other => panic!("unexpected deserialization outcome: {other:?}"),
+        };
+        assert_eq!(errors.len(), 6);
+        assert!(
+            matches!(&errors[0], CniDeserializationError::MalformedVersion { path, .. } if path == "cniVersions[1]")
+        );
+        assert!(matches!(
+            &errors[1],
+            CniDeserializationError::KeyOfWrongType { path, expected: CniJsonType::String, actual: CniJsonType::Number }
+                if path == "name"
+        ));
+        assert!(matches!(
+            &errors[2],
+            CniDeserializationError::KeyOfWrongType { path, expected: CniJsonType::Bool, actual: CniJsonType::String }
+                if path == "disableCheck"
+        ));
+        assert!(matches!(&errors[3], CniDeserializationError::MissingKey { path } if path == "plugins[1].type"));
+        assert!(matches!(
+            &errors[4],
+            CniDeserializationError::KeyOfWrongType { path, expected: CniJsonType::Object, actual: CniJsonType::Array }
+                if path == "plugins[3].capabilities"
+        ));
+        assert!(matches!(
+            &errors[5],
+            CniDeserializationError::KeyOfWrongType { path, expected: CniJsonType::Object, actual: CniJsonType::String }
+                if path == "plugins[4]"
+        ));
+    }
+
+    #[test]
+    fn single_deserialization_error_is_not_wrapped() {
+        assert!(matches!(
+            CniPluginList::from_json_value(json!({ "cniVersion": "1.0.0", "name": "testnet" })),
+            Err(CniDeserializationError::MissingKey { path }) if path == "plugins"
+        ));
+        assert!(matches!(
+            CniPluginList::from_json_value(json!({ "cniVersion": "1.0.0", "name": "testnet", "plugins": [] })),
+            Err(CniDeserializationError::EmptyArray { path }) if path == "plugins"
+        ));
+        assert!(matches!(
+            CniPlugin::from_json_value(json!({ "args": {} })),
+            Err(CniDeserializationError::MissingKey { path }) if path == "type"
+        ));
+        assert!(matches!(
+            CniPluginList::from_conf_json_value(json!({ "cniVersion": "1.0.0", "name": "net", "type": 1 })),
+            Err(CniDeserializationError::KeyOfWrongType { path, .. }) if path == "type"
+        ));
+    }
+}
@@ -1,13 +1,27 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, path::PathBuf};
 
+use crate::cache::{CniCache, CniCacheKey};
+use crate::convert;
 use crate::invocation::{
-    CniInvocationArguments, CniInvocationError, CniInvocationResult, CniInvocationTarget, CniInvoker, CniLocator,
+    CniInvocationArguments, CniInvocationError, CniInvocationResult, CniInvocationTarget, CniInvoker, CniInvokerOutput,
+    CniLocator, CniPluginInvocationError, CniSkipReason,
 };
-use crate::plugins::CniPlugin;
-use crate::types::{CniAttachment, CniError, CniOperation, CniVersionObject};
+use crate::plugins::{CniPlugin, CniPluginList};
+use crate::types::{CniArg, CniAttachment, CniError, CniOperation, CniVersion, CniVersionObject};
 use serde_json::Value;
 
 /// Perform a CNI invocation. This is the main function of tokio-cni.
+///
+/// CHECK and GC on plugin lists that set disableCheck or disableGC are skipped as the specification requires: no
+/// plugin gets executed and the returned result carries a skip reason. Operations that the effective CNI version
+/// doesn't define are rejected with [CniInvocationError::OperationNotSupportedByVersion] before spawning anything.
+///
+/// ADD and CHECK validate the network namespace first and fail with [CniInvocationError::MalformedNetworkNamespace]
+/// if it can't be used.
+///
+/// DEL and GC are best-effort: every plugin is invoked even if earlier ones fail, and all failures are reported
+/// together in [CniInvocationError::PluginsFailed]. If version negotiation fails for them, the configured CNI
+/// version is used instead.
 pub async fn invoke(
     operation: CniOperation,
     invocation_arguments: &CniInvocationArguments,
@@ -18,64 +32,312 @@ pub async fn invoke(
     let mut invocation_result = CniInvocationResult {
         attachment: None,
         version_objects: HashMap::new(),
+        skip_reason: None,
     };
 
-    match invocation_target {
-        CniInvocationTarget::Plugin {
+    if let CniInvocationTarget::PluginList(plugin_list) = invocation_target {
+        invocation_result.skip_reason = match operation {
+            CniOperation::Check if plugin_list.disable_check == Some(true) => Some(CniSkipReason::CheckDisabled),
+            CniOperation::GarbageCollect if plugin_list.disable_gc == Some(true) => {
+                Some(CniSkipReason::GarbageCollectDisabled)
+            }
+            _ => None,
+        };
+        if invocation_result.skip_reason.is_some() {
+            return Ok(invocation_result);
+        }
+    }
+
+    if let (CniOperation::Add | CniOperation::Check, Some(network_namespace)) =
+        (operation, &invocation_arguments.network_namespace)
+    {
+        network_namespace
+            .validate()
+            .await
+            .map_err(CniInvocationError::MalformedNetworkNamespace)?;
+    }
+
+    let best_effort = operation == CniOperation::Delete || operation == CniOperation::GarbageCollect;
+    let cni_version = match &invocation_arguments.cni_version {
+        Some(cni_version) => *cni_version,
+        None if invocation_arguments.negotiate_cni_version && operation != CniOperation::Version => {
+            // no point in asking the plugins if even the highest version they could agree on lacks the operation
+            let highest_cni_version = candidate_cni_versions(invocation_target)
+                .into_iter()
+                .max()
+                .unwrap_or(*invocation_target.cni_version());
+            if !highest_cni_version.supports_operation(operation) {
+                return Err(CniInvocationError::OperationNotSupportedByVersion {
+                    operation,
+                    cni_version: highest_cni_version,
+                });
+            }
+            match negotiate_cni_version(invocation_arguments, invocation_target, invoker, locator).await {
+                Ok(cni_version) => cni_version,
+                // a plugin that can't answer VERSION mustn't keep the others from releasing their resources
+                Err(_) if best_effort => *invocation_target.cni_version(),
+                Err(err) => return Err(err),
+            }
+        }
+        None => *invocation_target.cni_version(),
+    };
+
+    if !cni_version.supports_operation(operation) {
+        return Err(CniInvocationError::OperationNotSupportedByVersion { operation, cni_version });
+    }
+
+    let plugin_iter = match operation {
+        CniOperation::Delete => invocation_target.plugins().iter().rev().collect::<Vec<_>>(),
+        _ => invocation_target.plugins().iter().collect::<Vec<_>>(),
+    };
+
+    let mut failures = Vec::new();
+    let mut succeeded_plugins = Vec::new();
+
+    for (index, plugin) in plugin_iter.into_iter().enumerate() {
+        let invocation = invoke_plugin(
+            operation,
+            invocation_arguments,
             plugin,
-            cni_version: _,
-            name: _,
-        } => {
-            invoke_plugin(
-                operation,
+            invocation_target,
+            &cni_version,
+            &mut invocation_result,
+            invoker,
+            locator,
+        )
+        .await;
+
+        let error = match invocation {
+            Ok(()) => {
+                succeeded_plugins.push(plugin.plugin_type.clone());
+                continue;
+            }
+            Err(error) => error,
+        };
+        let failure = CniPluginInvocationError {
+            plugin_type: plugin.plugin_type.clone(),
+            error,
+        };
+
+        if best_effort {
+            failures.push(failure);
+        } else if operation == CniOperation::Add && invocation_arguments.rollback_on_failure {
+            return Err(rollback_add(
+                failure,
+                &invocation_target.plugins()[..index],
                 invocation_arguments,
-                plugin,
                 invocation_target,
+                &cni_version,
                 &mut invocation_result,
                 invoker,
                 locator,
             )
-            .await?;
+            .await);
+        } else {
+            return Err(failure.error);
         }
-        CniInvocationTarget::PluginList(plugin_list) => {
-            let plugin_iter = match operation {
-                CniOperation::Delete => plugin_list.plugins.iter().rev().collect::<Vec<_>>(),
-                _ => plugin_list.plugins.iter().collect::<Vec<_>>(),
-            };
-
-            for plugin in plugin_iter {
-                invoke_plugin(
-                    operation,
-                    invocation_arguments,
-                    plugin,
-                    invocation_target,
-                    &mut invocation_result,
-                    invoker,
-                    locator,
-                )
-                .await?;
+    }
+
+    if !failures.is_empty() {
+        return Err(CniInvocationError::PluginsFailed {
+            failures,
+            succeeded_plugins,
+        });
+    }
+
+    Ok(invocation_result)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn rollback_add(
+    failure: CniPluginInvocationError,
+    succeeded_plugins: &[CniPlugin],
+    invocation_arguments: &CniInvocationArguments,
+    invocation_target: &CniInvocationTarget<'_>,
+    cni_version: &CniVersion,
+    invocation_result: &mut CniInvocationResult,
+    invoker: &impl CniInvoker,
+    locator: &impl CniLocator,
+) -> CniInvocationError {
+    let mut rollback_failures = Vec::new();
+
+    for plugin in succeeded_plugins.iter().rev() {
+        if let Err(error) = invoke_plugin(
+            CniOperation::Delete,
+            invocation_arguments,
+            plugin,
+            invocation_target,
+            cni_version,
+            invocation_result,
+            invoker,
+            locator,
+        )
+        .await
+        {
+            rollback_failures.push(CniPluginInvocationError {
+                plugin_type: plugin.plugin_type.clone(),
+                error,
+            });
+        }
+    }
+
+    CniInvocationError::AddRolledBack {
+        failure: Box::new(failure),
+        rollback_failures,
+    }
+}
+
+/// Perform a CNI invocation like [invoke], but remember ADD results in the given cache. DEL and CHECK without an
+/// explicitly provided attachment will use the cached one as prevResult, and a successful DEL removes it.
+/// Invocations without a container ID or interface name bypass the cache.
+pub async fn invoke_cached(
+    operation: CniOperation,
+    invocation_arguments: &CniInvocationArguments,
+    invocation_target: &CniInvocationTarget<'_>,
+    invoker: &impl CniInvoker,
+    locator: &impl CniLocator,
+    cache: &impl CniCache,
+) -> Result<CniInvocationResult, CniInvocationError> {
+    let cache_key = match (&invocation_arguments.container_id, &invocation_arguments.interface_name) {
+        (Some(container_id), Some(interface_name)) => CniCacheKey::new(
+            invocation_target.name().clone(),
+            container_id.clone(),
+            interface_name.clone(),
+        ),
+        _ => return invoke(operation, invocation_arguments, invocation_target, invoker, locator).await,
+    };
+
+    let mut cached_arguments = None;
+    if invocation_arguments.attachment.is_none()
+        && (operation == CniOperation::Delete || operation == CniOperation::Check)
+    {
+        if let Some(attachment) = cache.get(&cache_key).await.map_err(CniInvocationError::CacheFailed)? {
+            let mut arguments = invocation_arguments.clone();
+            arguments.attachment(attachment);
+            cached_arguments = Some(arguments);
+        }
+    }
+
+    let invocation_result = invoke(
+        operation,
+        cached_arguments.as_ref().unwrap_or(invocation_arguments),
+        invocation_target,
+        invoker,
+        locator,
+    )
+    .await?;
+
+    match operation {
+        CniOperation::Add => {
+            if let Some(attachment) = &invocation_result.attachment {
+                cache
+                    .insert(&cache_key, attachment)
+                    .await
+                    .map_err(CniInvocationError::CacheFailed)?;
             }
         }
+        CniOperation::Delete => {
+            cache
+                .remove(&cache_key)
+                .await
+                .map_err(CniInvocationError::CacheFailed)?;
+        }
+        _ => {}
     }
 
     Ok(invocation_result)
 }
 
+/// Negotiate the highest CNI version that is both allowed by the target's configuration (cniVersions, falling back
+/// to cniVersion) and reported by every plugin's VERSION response.
+pub async fn negotiate_cni_version(
+    invocation_arguments: &CniInvocationArguments,
+    invocation_target: &CniInvocationTarget<'_>,
+    invoker: &impl CniInvoker,
+    locator: &impl CniLocator,
+) -> Result<CniVersion, CniInvocationError> {
+    let mut candidates = candidate_cni_versions(invocation_target);
+
+    let mut invocation_result = CniInvocationResult {
+        attachment: None,
+        version_objects: HashMap::new(),
+        skip_reason: None,
+    };
+
+    for plugin in invocation_target.plugins() {
+        invoke_plugin(
+            CniOperation::Version,
+            invocation_arguments,
+            plugin,
+            invocation_target,
+            invocation_target.cni_version(),
+            &mut invocation_result,
+            invoker,
+            locator,
+        )
+        .await?;
+
+        let version_object = invocation_result
+            .version_objects
+            .get(&plugin.plugin_type)
+            .ok_or_else(|| CniInvocationError::NoCommonCniVersion {
+                plugin_type: plugin.plugin_type.clone(),
+            })?;
+        candidates.retain(|candidate| version_object.supported_versions.contains(candidate));
+
+        if candidates.is_empty() {
+            return Err(CniInvocationError::NoCommonCniVersion {
+                plugin_type: plugin.plugin_type.clone(),
+            });
+        }
+    }
+
+    Ok(candidates.into_iter().max().unwrap_or(*invocation_target.cni_version()))
+}
+
+/// The directories passed to plugins as CNI_PATH, which must be the ones the locator searched if it exposes them.
+fn plugin_paths<'a>(
+    invocation_arguments: &'a CniInvocationArguments,
+    locator: &'a impl CniLocator,
+) -> Result<&'a [PathBuf], CniInvocationError> {
+    match (&invocation_arguments.paths, locator.search_paths()) {
+        (Some(paths), search_paths) if !search_paths.is_empty() && paths.as_slice() != search_paths => {
+            Err(CniInvocationError::ConflictingPluginPaths {
+                paths: paths.clone(),
+                search_paths: search_paths.to_vec(),
+            })
+        }
+        (Some(paths), _) => Ok(paths),
+        (None, search_paths) => Ok(search_paths),
+    }
+}
+
+/// The versions negotiation can settle on: the plugin list's cniVersions if present, otherwise its cniVersion.
+fn candidate_cni_versions(invocation_target: &CniInvocationTarget<'_>) -> Vec<CniVersion> {
+    match invocation_target {
+        CniInvocationTarget::PluginList(CniPluginList {
+            cni_versions: Some(cni_versions),
+            ..
+        }) => cni_versions.clone(),
+        _ => vec![*invocation_target.cni_version()],
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn invoke_plugin(
     operation: CniOperation,
     invocation_arguments: &CniInvocationArguments,
     plugin: &CniPlugin,
     invocation_target: &CniInvocationTarget<'_>,
+    cni_version: &CniVersion,
     invocation_output: &mut CniInvocationResult,
     invoker: &impl CniInvoker,
     locator: &impl CniLocator,
 ) -> Result<(), CniInvocationError> {
-    let location = match locator.locate(&plugin.plugin_type).await {
-        Some(location) => location,
-        None => {
-            return Err(CniInvocationError::PluginNotFoundByLocator);
-        }
-    };
+    let location = locator
+        .locate(&plugin.plugin_type)
+        .await
+        .map_err(CniInvocationError::PluginNotFoundByLocator)?;
 
     let mut environment: HashMap<String, String> = HashMap::new();
     environment.insert(
@@ -102,64 +364,103 @@ async fn invoke_plugin(
         environment.insert("CNI_IFNAME".into(), interface_name.as_ref().into());
     }
 
-    if let Some(paths) = &invocation_arguments.paths {
-        if !paths.is_empty() {
-            let path_str = paths
-                .iter()
-                .map(|p| p.to_string_lossy().into_owned())
-                .collect::<Vec<_>>()
-                .join(":");
-            environment.insert("CNI_PATH".into(), path_str);
+    if let Some(cni_args) = &invocation_arguments.cni_args {
+        if !cni_args.is_empty() {
+            environment.insert("CNI_ARGS".into(), CniArg::format_list(cni_args));
         }
     }
 
+    let paths = plugin_paths(invocation_arguments, locator)?;
+    if !paths.is_empty() {
+        let path_str = paths
+            .iter()
+            .map(|p| p.to_string_lossy().into_owned())
+            .collect::<Vec<_>>()
+            .join(":");
+        environment.insert("CNI_PATH".into(), path_str);
+    }
+
     let previous_attachment = invocation_arguments
         .attachment
         .as_ref()
         .or(invocation_output.attachment.as_ref());
-    let stdin = derive_stdin(plugin, &invocation_arguments, invocation_target, previous_attachment)?;
-    let cni_output = invoker
-        .invoke(&location, environment, stdin)
-        .await
-        .map_err(CniInvocationError::InvokerFailed)?;
+    let stdin = derive_stdin(
+        plugin,
+        invocation_arguments,
+        invocation_target,
+        cni_version,
+        previous_attachment,
+    )?;
+    let invocation = invoker.invoke(&location, environment, stdin);
+    let cni_output = match invocation_arguments.timeout_for(operation, &plugin.plugin_type) {
+        Some(timeout) => tokio::time::timeout(timeout, invocation)
+            .await
+            .map_err(|_| CniInvocationError::TimedOut {
+                plugin_type: plugin.plugin_type.clone(),
+                timeout,
+            })?,
+        None => invocation.await,
+    }
+    .map_err(CniInvocationError::InvokerFailed)?;
 
     add_to_invocation_result(cni_output, plugin, invocation_output)?;
 
     Ok(())
 }
 
+/// Interpret a plugin's output as the specification requires: a zero exit code means stdout holds the result (if
+/// any), while any other exit code means stdout holds an error. Stderr is never parsed, only carried along.
 fn add_to_invocation_result(
-    cni_output: String,
+    cni_output: CniInvokerOutput,
     plugin: &CniPlugin,
     invocation_output: &mut CniInvocationResult,
 ) -> Result<(), CniInvocationError> {
-    if let Ok(version_object) = serde_json::from_str::<CniVersionObject>(&cni_output) {
+    if !cni_output.success() {
+        return match serde_json::from_str::<CniError>(&cni_output.stdout) {
+            Ok(error) => Err(CniInvocationError::PluginProducedError {
+                error,
+                stderr: cni_output.stderr,
+            }),
+            Err(_) => Err(unrecognizable_output(cni_output)),
+        };
+    }
+
+    if let Ok(version_object) = serde_json::from_str::<CniVersionObject>(&cni_output.stdout) {
         invocation_output
             .version_objects
             .insert(plugin.plugin_type.clone(), version_object);
         return Ok(());
     }
 
-    if let Ok(attachment) = serde_json::from_str::<CniAttachment>(&cni_output) {
-        invocation_output.attachment = Some(attachment);
-        return Ok(());
-    }
-
-    if let Ok(error) = serde_json::from_str::<CniError>(&cni_output) {
-        return Err(CniInvocationError::PluginProducedError(error));
+    // only an object that declares its version is a result, anything else is unrecognizable rather than malformed
+    if let Ok(value @ Value::Object(_)) = serde_json::from_str::<Value>(&cni_output.stdout) {
+        if value.get("cniVersion").is_some() {
+            let attachment = convert::parse_attachment(value).map_err(CniInvocationError::ConversionFailed)?;
+            invocation_output.attachment = Some(attachment);
+            return Ok(());
+        }
     }
 
-    if cni_output.trim().is_empty() {
+    if cni_output.stdout.trim().is_empty() {
         return Ok(());
     }
 
-    Err(CniInvocationError::PluginProducedUnrecognizableOutput(cni_output))
+    Err(unrecognizable_output(cni_output))
+}
+
+fn unrecognizable_output(cni_output: CniInvokerOutput) -> CniInvocationError {
+    CniInvocationError::PluginProducedUnrecognizableOutput {
+        exit_code: cni_output.exit_code,
+        stdout: cni_output.stdout,
+        stderr: cni_output.stderr,
+    }
 }
 
 fn derive_stdin(
     plugin: &CniPlugin,
     arguments: &CniInvocationArguments,
     invocation_target: &CniInvocationTarget,
+    cni_version: &CniVersion,
     previous_attachment: Option<&CniAttachment>,
 ) -> Result<String, CniInvocationError> {
     // plugin options
@@ -169,33 +470,22 @@ fn derive_stdin(
     map.insert("type".into(), Value::String(plugin.plugin_type.clone()));
 
     // name
-    let network_name: String = match invocation_target {
-        CniInvocationTarget::Plugin {
-            plugin: _,
-            cni_version: _,
-            name,
-        } => name.as_ref().to_owned(),
-        CniInvocationTarget::PluginList(plugin_list) => plugin_list.name.as_ref().to_owned(),
-    };
-    map.insert("name".into(), Value::String(network_name));
+    map.insert(
+        "name".into(),
+        Value::String(invocation_target.name().as_ref().to_owned()),
+    );
 
-    // cni version with override possibility
-    let mut cni_version = match invocation_target {
-        CniInvocationTarget::Plugin {
-            plugin: _,
-            cni_version,
-            name: _,
-        } => cni_version.clone(),
-        CniInvocationTarget::PluginList(plugin_list) => plugin_list.cni_version.clone(),
-    };
-    if let Some(new_cni_version) = &arguments.cni_version {
-        cni_version = new_cni_version.clone();
-    }
-    map.insert("cniVersion".into(), Value::String(cni_version.as_ref().to_owned()));
+    // cni version
+    map.insert("cniVersion".into(), Value::String(cni_version.to_string()));
 
-    // capabilities as runtimeConfig
-    if let Some(capabilities) = &plugin.capabilities {
-        map.insert("runtimeConfig".into(), Value::Object(capabilities.clone()));
+    // capability args as runtimeConfig, only for capabilities enabled by the plugin
+    if let (Some(capabilities), Some(capability_args)) = (&plugin.capabilities, &arguments.capability_args) {
+        let runtime_config = capability_args
+            .to_runtime_config(capabilities)
+            .map_err(CniInvocationError::JsonOperationFailed)?;
+        if !runtime_config.is_empty() {
+            map.insert("runtimeConfig".into(), Value::Object(runtime_config));
+        }
     }
 
     // args
@@ -203,9 +493,10 @@ fn derive_stdin(
         map.insert("args".into(), Value::Object(args.clone()));
     }
 
-    // previous attachment as prevResult
+    // previous attachment as prevResult, in the result format of the cni version
     if let Some(attachment) = previous_attachment {
-        let attachment_value = serde_json::to_value(attachment).map_err(CniInvocationError::JsonOperationFailed)?;
+        let attachment_value =
+            convert::attachment_to_value(attachment, *cni_version).map_err(CniInvocationError::ConversionFailed)?;
         map.insert("prevResult".into(), attachment_value);
     }
 
@@ -1,9 +1,16 @@
-use std::{net::IpAddr, path::PathBuf, str::FromStr, vec};
+use std::{
+    fmt::{self, Display, Formatter},
+    net::IpAddr,
+    path::PathBuf,
+    str::FromStr,
+};
 
 use cidr::IpInet;
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+use crate::netns::CniNetns;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum CniOperation {
     Add,
     Delete,
@@ -13,6 +20,26 @@ pub enum CniOperation {
     GarbageCollect,
 }
 
+impl CniOperation {
+    pub const ALL: [CniOperation; 6] = [
+        CniOperation::Add,
+        CniOperation::Delete,
+        CniOperation::Check,
+        CniOperation::Version,
+        CniOperation::Status,
+        CniOperation::GarbageCollect,
+    ];
+
+    /// The earliest CNI specification version that defines this operation.
+    pub fn minimum_cni_version(&self) -> CniVersion {
+        match self {
+            CniOperation::Add | CniOperation::Delete | CniOperation::Version => CniVersion::new(0, 1, 0),
+            CniOperation::Check => CniVersion::new(0, 4, 0),
+            CniOperation::Status | CniOperation::GarbageCollect => CniVersion::new(1, 1, 0),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct CniAttachment {
     #[serde(rename = "cniVersion")]
@@ -23,36 +50,48 @@ pub struct CniAttachment {
     pub ips: Vec<CniAttachmentIp>,
     #[serde(default)]
     pub routes: Vec<CniAttachmentRoute>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub dns: Option<CniAttachmentDns>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct CniAttachmentInterface {
     pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub mac: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub mtu: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub sandbox: Option<String>,
-    #[serde(rename = "socketPath")]
+    #[serde(rename = "socketPath", skip_serializing_if = "Option::is_none")]
     pub socket_path: Option<String>,
-    #[serde(rename = "pciID")]
+    #[serde(rename = "pciID", skip_serializing_if = "Option::is_none")]
     pub pci_id: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct CniAttachmentIp {
     pub address: IpInet,
-    pub gateway: IpAddr,
-    pub interface: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gateway: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct CniAttachmentRoute {
     pub dst: IpInet,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub gw: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub mtu: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub advmss: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub priority: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub table: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub scope: Option<u32>,
 }
 
@@ -60,6 +99,7 @@ pub struct CniAttachmentRoute {
 pub struct CniAttachmentDns {
     #[serde(default)]
     pub nameservers: Vec<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub domain: Option<String>,
     #[serde(default)]
     pub search: Vec<String>,
@@ -94,6 +134,10 @@ pub enum CniValidationError {
     IncorrectSplitAmount,
     SplitMissing,
     SplitNotParseable(<u8 as FromStr>::Err),
+    PathIsNotAbsolute,
+    PathDoesNotExist,
+    PathIsInaccessible,
+    PathIsNotNetworkNamespace,
 }
 
 #[derive(Serialize, Debug, Clone, PartialEq, Eq)]
@@ -104,7 +148,7 @@ pub struct CniValidAttachment {
     pub interface_name: String,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct CniContainerId(String);
 
 impl CniContainerId {
@@ -118,7 +162,7 @@ impl CniContainerId {
             return Err(CniValidationError::FirstIsNotAlphabetic);
         }
 
-        let allowed_chars = vec!['.', '_', '-'];
+        let allowed_chars = ['.', '_', '-'];
         if !container_id
             .as_bytes()
             .iter()
@@ -143,7 +187,7 @@ impl From<CniContainerId> for String {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct CniName(String);
 
 impl CniName {
@@ -177,7 +221,42 @@ impl From<CniName> for String {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+/// The name of a network namespace persisted in a directory such as /run/netns. Any file name is allowed, unlike
+/// network names, so names like `cni-1234` that container runtimes generate are accepted.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CniNetnsName(String);
+
+impl CniNetnsName {
+    pub fn new(netns_name: impl Into<String>) -> Result<CniNetnsName, CniValidationError> {
+        let netns_name = netns_name.into();
+
+        if netns_name.is_empty() {
+            return Err(CniValidationError::IsEmptyOrBlank);
+        }
+        if netns_name == "." || netns_name == ".." {
+            return Err(CniValidationError::IsForbiddenValue);
+        }
+        if netns_name.contains(['/', '\0']) {
+            return Err(CniValidationError::ContainsForbiddenCharacter);
+        }
+
+        Ok(CniNetnsName(netns_name))
+    }
+}
+
+impl AsRef<str> for CniNetnsName {
+    fn as_ref(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl From<CniNetnsName> for String {
+    fn from(value: CniNetnsName) -> Self {
+        value.0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct CniInterfaceName(String);
 
 static IFNAME_MAX_LENGTH: usize = 15;
@@ -198,7 +277,7 @@ impl CniInterfaceName {
             return Err(CniValidationError::IsForbiddenValue);
         }
 
-        let forbidden_chars = vec![' ', ':', '/'];
+        let forbidden_chars = [' ', ':', '/'];
         if interface_name
             .as_bytes()
             .iter()
@@ -223,12 +302,110 @@ impl From<CniInterfaceName> for String {
     }
 }
 
+/// A single key-value pair passed to plugins through the CNI_ARGS environment variable, where pairs are separated
+/// by ";" and keys from values by "=".
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CniArg {
+    key: String,
+    value: String,
+}
+
+static CNI_ARGS_FORBIDDEN_CHARS: [char; 2] = [';', '='];
+
+impl CniArg {
+    pub fn new(key: impl Into<String>, value: impl Into<String>) -> Result<CniArg, CniValidationError> {
+        let key = key.into();
+        let value = value.into();
+
+        if key.trim().is_empty() {
+            return Err(CniValidationError::IsEmptyOrBlank);
+        }
+        if key.contains(CNI_ARGS_FORBIDDEN_CHARS) || value.contains(CNI_ARGS_FORBIDDEN_CHARS) {
+            return Err(CniValidationError::ContainsForbiddenCharacter);
+        }
+
+        Ok(CniArg { key, value })
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn parse_list(value: impl AsRef<str>) -> Result<Vec<CniArg>, CniValidationError> {
+        if value.as_ref().is_empty() {
+            return Ok(Vec::new());
+        }
+
+        value.as_ref().split(';').map(CniArg::parse).collect()
+    }
+
+    pub fn format_list(args: &[CniArg]) -> String {
+        args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>().join(";")
+    }
+
+    pub fn find<'a>(args: &'a [CniArg], key: &str) -> Option<&'a str> {
+        args.iter().find(|arg| arg.key == key).map(|arg| arg.value())
+    }
+
+    pub fn parse(value: impl AsRef<str>) -> Result<CniArg, CniValidationError> {
+        match value.as_ref().split('=').collect::<Vec<_>>()[..] {
+            [key, value] => CniArg::new(key, value),
+            _ => Err(CniValidationError::IncorrectSplitAmount),
+        }
+    }
+}
+
+impl FromStr for CniArg {
+    type Err = CniValidationError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        CniArg::parse(value)
+    }
+}
+
+impl Display for CniArg {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.key, self.value)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum CniNetworkNamespace {
     LinuxNamespace(PathBuf),
     Custom(CniName),
 }
 
+impl CniNetworkNamespace {
+    /// Check that a Linux network namespace path is absolute, exists and refers to a network namespace. Custom
+    /// namespaces are opaque to the runtime and always pass.
+    pub async fn validate(&self) -> Result<(), CniValidationError> {
+        let path = match self {
+            CniNetworkNamespace::LinuxNamespace(path) => path,
+            CniNetworkNamespace::Custom(_) => return Ok(()),
+        };
+
+        if !path.is_absolute() {
+            return Err(CniValidationError::PathIsNotAbsolute);
+        }
+
+        match tokio::fs::try_exists(path).await {
+            Ok(true) => {}
+            Ok(false) => return Err(CniValidationError::PathDoesNotExist),
+            Err(_) => return Err(CniValidationError::PathIsInaccessible),
+        }
+
+        match CniNetns::is_network_namespace(path).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(CniValidationError::PathIsNotNetworkNamespace),
+            Err(_) => Err(CniValidationError::PathIsInaccessible),
+        }
+    }
+}
+
 impl From<&CniNetworkNamespace> for String {
     fn from(value: &CniNetworkNamespace) -> Self {
         match value {
@@ -238,12 +415,17 @@ impl From<&CniNetworkNamespace> for String {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
-pub struct CniVersion(String);
+/// A semantic CNI specification version. Versions are ordered numerically, so "0.10.0" sorts after "0.4.0".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CniVersion {
+    major: u8,
+    minor: u8,
+    patch: u8,
+}
 
 impl CniVersion {
-    pub fn new(major: u8, minor: u8, patch: u8) -> CniVersion {
-        CniVersion(format!("{major}.{minor}.{patch}"))
+    pub const fn new(major: u8, minor: u8, patch: u8) -> CniVersion {
+        CniVersion { major, minor, patch }
     }
 
     pub fn parse(value: impl AsRef<str>) -> Result<CniVersion, CniValidationError> {
@@ -259,34 +441,95 @@ impl CniVersion {
         Ok(CniVersion::new(major, minor, patch))
     }
 
-    fn parse_split(splits: &Vec<&str>, index: usize) -> Result<u8, CniValidationError> {
+    fn parse_split(splits: &[&str], index: usize) -> Result<u8, CniValidationError> {
         splits
             .get(index)
             .ok_or(CniValidationError::SplitMissing)?
             .parse()
             .map_err(CniValidationError::SplitNotParseable)
     }
+
+    pub fn major(&self) -> u8 {
+        self.major
+    }
+
+    pub fn minor(&self) -> u8 {
+        self.minor
+    }
+
+    pub fn patch(&self) -> u8 {
+        self.patch
+    }
+
+    pub fn supports_operation(&self, operation: CniOperation) -> bool {
+        *self >= operation.minimum_cni_version()
+    }
+
+    pub fn supported_operations(&self) -> Vec<CniOperation> {
+        CniOperation::ALL
+            .into_iter()
+            .filter(|operation| self.supports_operation(*operation))
+            .collect()
+    }
+
+    pub fn supports_check(&self) -> bool {
+        self.supports_operation(CniOperation::Check)
+    }
+
+    pub fn supports_status(&self) -> bool {
+        self.supports_operation(CniOperation::Status)
+    }
+
+    pub fn supports_garbage_collect(&self) -> bool {
+        self.supports_operation(CniOperation::GarbageCollect)
+    }
 }
 
-impl AsRef<str> for CniVersion {
-    fn as_ref(&self) -> &str {
-        &self.0
+impl FromStr for CniVersion {
+    type Err = CniValidationError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        CniVersion::parse(value)
+    }
+}
+
+impl Display for CniVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
     }
 }
 
 impl From<CniVersion> for String {
     fn from(value: CniVersion) -> Self {
-        value.0
+        value.to_string()
+    }
+}
+
+impl Serialize for CniVersion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for CniVersion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        CniVersion::parse(&value).map_err(|err| de::Error::custom(format!("malformed CNI version {value:?}: {err:?}")))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::types::{CniContainerId, CniInterfaceName, CniName, CniValidationError, CniVersion, IFNAME_MAX_LENGTH};
+    use std::path::PathBuf;
+
+    use crate::types::{
+        CniArg, CniContainerId, CniInterfaceName, CniName, CniNetnsName, CniNetworkNamespace, CniOperation,
+        CniValidationError, CniVersion, IFNAME_MAX_LENGTH,
+    };
 
     #[test]
     fn container_id_rejects_empty_or_blank() {
-        for container_id in vec!["", "   "] {
+        for container_id in ["", "   "] {
             assert_eq!(
                 CniContainerId::new(container_id),
                 Err(CniValidationError::IsEmptyOrBlank)
@@ -296,7 +539,7 @@ mod tests {
 
     #[test]
     fn container_id_rejects_first_nonalphabetic() {
-        for container_id in vec!["1abc", "мabc", "!abc", "_abc", ":abc", ".abc"] {
+        for container_id in ["1abc", "мabc", "!abc", "_abc", ":abc", ".abc"] {
             assert_eq!(
                 CniContainerId::new(container_id),
                 Err(CniValidationError::FirstIsNotAlphabetic)
@@ -306,7 +549,7 @@ mod tests {
 
     #[test]
     fn container_id_rejects_invalid_chars() {
-        for container_id in vec!["a!bc", "a:bc", "a$bc", "a^bc", "a{bc", "a}bc"] {
+        for container_id in ["a!bc", "a:bc", "a$bc", "a^bc", "a{bc", "a}bc"] {
             assert_eq!(
                 CniContainerId::new(container_id),
                 Err(CniValidationError::ContainsForbiddenCharacter)
@@ -316,42 +559,63 @@ mod tests {
 
     #[test]
     fn container_id_accepts_valid() {
-        for container_id in vec!["abc", "a1bc", "AbC", "A_bc", "A.bc", "A-bc"] {
+        for container_id in ["abc", "a1bc", "AbC", "A_bc", "A.bc", "A-bc"] {
             assert_eq!(CniContainerId::new(container_id).unwrap().as_ref(), container_id);
         }
     }
 
     #[test]
     fn name_rejects_empty_or_blank() {
-        for name in vec!["", "   "] {
+        for name in ["", "   "] {
             assert_eq!(CniName::new(name), Err(CniValidationError::IsEmptyOrBlank));
         }
     }
 
     #[test]
     fn name_rejects_non_alphabetic_first_char() {
-        for name in vec!["1abc", "_abc", ":abc", "!abc", "лabc", "~abc"] {
+        for name in ["1abc", "_abc", ":abc", "!abc", "лabc", "~abc"] {
             assert_eq!(CniName::new(name), Err(CniValidationError::FirstIsNotAlphabetic));
         }
     }
 
     #[test]
     fn name_rejects_non_alphanumeric_non_first_char() {
-        for name in vec!["a!c", "a:c", "a.c", "a_c"] {
+        for name in ["a!c", "a:c", "a.c", "a_c"] {
             assert_eq!(CniName::new(name), Err(CniValidationError::ContainsForbiddenCharacter));
         }
     }
 
     #[test]
     fn name_accepts_valid() {
-        for name in vec!["abc", "Abc", "AbC", "A0C", "aC0", "a6bbB1"] {
+        for name in ["abc", "Abc", "AbC", "A0C", "aC0", "a6bbB1"] {
             assert_eq!(CniName::new(name).unwrap().as_ref(), name);
         }
     }
 
+    #[test]
+    fn netns_name_rejects_path_components() {
+        assert_eq!(CniNetnsName::new(""), Err(CniValidationError::IsEmptyOrBlank));
+        for netns_name in [".", ".."] {
+            assert_eq!(CniNetnsName::new(netns_name), Err(CniValidationError::IsForbiddenValue));
+        }
+        for netns_name in ["a/b", "/abc", "../abc", "a\0b"] {
+            assert_eq!(
+                CniNetnsName::new(netns_name),
+                Err(CniValidationError::ContainsForbiddenCharacter)
+            );
+        }
+    }
+
+    #[test]
+    fn netns_name_accepts_valid() {
+        for netns_name in ["cni-1234", "test_ns", "a.b", "...", "1abc"] {
+            assert_eq!(CniNetnsName::new(netns_name).unwrap().as_ref(), netns_name);
+        }
+    }
+
     #[test]
     fn interface_name_rejects_empty_or_blank() {
-        for interface_name in vec!["", " ", "  ", "   "] {
+        for interface_name in ["", " ", "  ", "   "] {
             assert_eq!(
                 CniInterfaceName::new(interface_name),
                 Err(CniValidationError::IsEmptyOrBlank)
@@ -372,7 +636,7 @@ mod tests {
 
     #[test]
     fn interface_name_rejects_forbidden_values() {
-        for interface_name in vec![".", ".."] {
+        for interface_name in [".", ".."] {
             assert_eq!(
                 CniInterfaceName::new(interface_name),
                 Err(CniValidationError::IsForbiddenValue)
@@ -382,7 +646,7 @@ mod tests {
 
     #[test]
     fn interface_name_rejects_forbidden_chars() {
-        for interface_name in vec!["a c", "a:c", "a/c"] {
+        for interface_name in ["a c", "a:c", "a/c"] {
             assert_eq!(
                 CniInterfaceName::new(interface_name),
                 Err(CniValidationError::ContainsForbiddenCharacter)
@@ -392,22 +656,125 @@ mod tests {
 
     #[test]
     fn interface_name_accepts_valid() {
-        for interface_name in vec!["standard_ifname", "another_ifname", "last"] {
+        for interface_name in ["standard_ifname", "another_ifname", "last"] {
             assert_eq!(CniInterfaceName::new(interface_name).unwrap().as_ref(), interface_name);
         }
     }
 
     #[test]
     fn version_doesnt_parse_malformed() {
-        for version in vec!["0.0", "0.0.0.0", "", " "] {
+        for version in ["0.0", "0.0.0.0", "", " "] {
             assert_eq!(
                 CniVersion::parse(version),
                 Err(CniValidationError::IncorrectSplitAmount)
             );
         }
 
-        for version in vec!["a.0.0", "0.b.0", "0.0.c", "!.:.>"] {
+        for version in ["a.0.0", "0.b.0", "0.0.c", "!.:.>"] {
             assert!(CniVersion::parse(version).is_err());
         }
     }
+
+    #[test]
+    fn version_orders_numerically() {
+        let mut versions = ["1.0.0", "0.10.0", "0.4.0", "0.3.1", "1.1.0", "0.3.0"]
+            .map(|version| CniVersion::parse(version).unwrap())
+            .to_vec();
+        versions.sort();
+        assert_eq!(
+            versions.iter().map(|version| version.to_string()).collect::<Vec<_>>(),
+            ["0.3.0", "0.3.1", "0.4.0", "0.10.0", "1.0.0", "1.1.0"]
+        );
+    }
+
+    #[test]
+    fn version_round_trips_through_serde() {
+        let version = CniVersion::new(0, 10, 2);
+        let serialized = serde_json::to_string(&version).unwrap();
+        assert_eq!(serialized, "\"0.10.2\"");
+        assert_eq!(serde_json::from_str::<CniVersion>(&serialized).unwrap(), version);
+        assert!(serde_json::from_str::<CniVersion>("\"1.0\"").is_err());
+    }
+
+    #[test]
+    fn version_gates_operations() {
+        let version = CniVersion::new(0, 3, 1);
+        assert!(!version.supports_check());
+        assert_eq!(
+            version.supported_operations(),
+            [CniOperation::Add, CniOperation::Delete, CniOperation::Version]
+        );
+
+        let version = CniVersion::new(1, 0, 0);
+        assert!(version.supports_check());
+        assert!(!version.supports_status());
+        assert!(!version.supports_garbage_collect());
+
+        let version = CniVersion::new(1, 1, 0);
+        assert!(version.supports_status());
+        assert!(version.supports_garbage_collect());
+        assert_eq!(version.supported_operations(), CniOperation::ALL);
+    }
+
+    #[test]
+    fn arg_rejects_invalid() {
+        assert_eq!(CniArg::new(" ", "value"), Err(CniValidationError::IsEmptyOrBlank));
+        for (key, value) in [
+            ("K;EY", "value"),
+            ("K=EY", "value"),
+            ("KEY", "va;lue"),
+            ("KEY", "va=lue"),
+        ] {
+            assert_eq!(
+                CniArg::new(key, value),
+                Err(CniValidationError::ContainsForbiddenCharacter)
+            );
+        }
+    }
+
+    #[test]
+    fn arg_list_round_trips() {
+        let args = vec![
+            CniArg::new("IgnoreUnknown", "1").unwrap(),
+            CniArg::new("K8S_POD_NAME", "my pod").unwrap(),
+            CniArg::new("IP", "").unwrap(),
+        ];
+        let formatted = CniArg::format_list(&args);
+        assert_eq!(formatted, "IgnoreUnknown=1;K8S_POD_NAME=my pod;IP=");
+        assert_eq!(CniArg::parse_list(&formatted).unwrap(), args);
+        assert_eq!(CniArg::find(&args, "K8S_POD_NAME"), Some("my pod"));
+        assert_eq!(CniArg::find(&args, "K8S_POD_NAMESPACE"), None);
+    }
+
+    #[test]
+    fn arg_list_doesnt_parse_malformed() {
+        assert_eq!(CniArg::parse_list("").unwrap(), Vec::new());
+        for args in ["IP", "IP=1=2", "IP=1;", "IP=1;;A=2"] {
+            assert_eq!(CniArg::parse_list(args), Err(CniValidationError::IncorrectSplitAmount));
+        }
+    }
+
+    #[tokio::test]
+    async fn network_namespace_validation() {
+        let validate = |path: &str| CniNetworkNamespace::LinuxNamespace(PathBuf::from(path));
+        assert_eq!(validate("/proc/self/ns/net").validate().await, Ok(()));
+        assert_eq!(
+            validate("proc/self/ns/net").validate().await,
+            Err(CniValidationError::PathIsNotAbsolute)
+        );
+        assert_eq!(
+            validate("/run/netns/nonexistent-tokio-cni").validate().await,
+            Err(CniValidationError::PathDoesNotExist)
+        );
+        assert_eq!(
+            validate("/usr").validate().await,
+            Err(CniValidationError::PathIsNotNetworkNamespace)
+        );
+        assert_eq!(
+            CniNetworkNamespace::Custom(CniName::new("vm").unwrap())
+                .validate()
+                .await,
+            Ok(())
+        );
+    }
 }
@@ -11,7 +11,7 @@ use tokio_cni::{
 async fn t() {
     let locator = DirectoryCniLocator {
         directory_path: PathBuf::from("/usr/libexec/cni"),
-        exact_name: true,
+        suffixes: Vec::new(),
     };
     let invoker = SuCniInvoker {
         su_path: PathBuf::from("/usr/bin/su"),
@@ -25,7 +25,7 @@ async fn t() {
     let mut arguments = CniInvocationArguments::new();
     arguments
         .container_id(CniContainerId::new("fcnet").unwrap())
-        .network_namespace(CniNetworkNamespace::LinuxNamespace(PathBuf::from("/usr/libexec/cni")))
+        .network_namespace(CniNetworkNamespace::LinuxNamespace(PathBuf::from("/run/netns/fcnet")))
         .interface_name(CniInterfaceName::new("eth0").unwrap())
         .paths(vec!["/usr/libexec/cni"]);
 
@@ -0,0 +1,313 @@
+mod common;
+
+use std::{collections::HashMap, os::unix::fs::PermissionsExt, path::Path, time::Duration};
+
+use common::TemporaryDirectory;
+use tokio_cni::{
+    invocation::{
+        CniInvocationArguments, CniInvocationError, CniInvocationTarget, CniInvoker, MappedCniLocator,
+        PkexecCniInvoker, RootfulCniInvoker, RootlessCniInvoker, SuCniInvoker, SudoCniInvoker,
+    },
+    plugins::{CniDeserializable, CniPluginList},
+    runtime::invoke,
+    types::{CniContainerId, CniInterfaceName, CniNetworkNamespace, CniOperation},
+};
+
+fn write_script(path: &Path, content: &str) {
+    std::fs::write(path, format!("#!/bin/sh\n{content}")).unwrap();
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+fn is_process_dead(pid: &str) -> bool {
+    match std::fs::read_to_string(format!("/proc/{pid}/stat")) {
+        Ok(stat) => stat.rsplit(')').next().unwrap().trim_start().starts_with('Z'),
+        Err(_) => true,
+    }
+}
+
+fn plugin_list() -> CniPluginList {
+    CniPluginList::from_string(r#"{ "cniVersion": "1.0.0", "name": "testnet", "plugins": [{ "type": "slow" }] }"#)
+        .unwrap()
+}
+
+fn arguments() -> CniInvocationArguments {
+    let mut arguments = CniInvocationArguments::new();
+    arguments
+        .container_id(CniContainerId::new("container").unwrap())
+        .interface_name(CniInterfaceName::new("eth0").unwrap());
+    arguments
+}
+
+#[tokio::test]
+async fn timed_out_plugin_process_group_is_killed() {
+    let directory = TemporaryDirectory::new("timeout");
+    let pid_path = directory.join("pid");
+    let plugin_path = directory.join("slow");
+    write_script(
+        &plugin_path,
+        &format!("sleep 30 &\necho $! > {}\nwait\n", pid_path.display()),
+    );
+    let locator = MappedCniLocator {
+        lookup_map: HashMap::from([("slow".into(), plugin_path)]),
+    };
+    let plugin_list = plugin_list();
+    let target = CniInvocationTarget::PluginList(&plugin_list);
+
+    let mut arguments = arguments();
+    arguments
+        .timeout(Duration::from_secs(10))
+        .operation_timeout(CniOperation::Add, Duration::from_millis(500));
+    let result = invoke(CniOperation::Add, &arguments, &target, &RootfulCniInvoker {}, &locator).await;
+    match result {
+        Err(CniInvocationError::TimedOut { plugin_type, timeout }) => {
+            assert_eq!(plugin_type, "slow");
+            assert_eq!(timeout, Duration::from_millis(500));
+        }
+        other => panic!("unexpected invocation outcome: {other:?}"),
+    }
+
+    let background_pid = std::fs::read_to_string(&pid_path).unwrap();
+    let mut attempts = 0;
+    while !is_process_dead(background_pid.trim()) {
+        attempts += 1;
+        assert!(attempts < 50, "background process of the plugin was orphaned");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[tokio::test]
+async fn plugin_timeout_takes_precedence() {
+    let directory = TemporaryDirectory::new("plugin-timeout");
+    let plugin_path = directory.join("slow");
+    write_script(&plugin_path, "sleep 1\n");
+    let locator = MappedCniLocator {
+        lookup_map: HashMap::from([("slow".into(), plugin_path)]),
+    };
+    let plugin_list = plugin_list();
+    let target = CniInvocationTarget::PluginList(&plugin_list);
+
+    let mut arguments = arguments();
+    arguments
+        .timeout(Duration::from_millis(100))
+        .plugin_timeout("slow", Duration::from_secs(10));
+    invoke(CniOperation::Add, &arguments, &target, &RootfulCniInvoker {}, &locator)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn plugin_may_exit_without_reading_stdin() {
+    let directory = TemporaryDirectory::new("unread-stdin");
+    let plugin_path = directory.join("plugin");
+    write_script(&plugin_path, "exit 3\n");
+
+    let output = RootfulCniInvoker {}
+        .invoke(&plugin_path, HashMap::new(), "x".repeat(1 << 20))
+        .await
+        .unwrap();
+    assert_eq!(output.exit_code, Some(3));
+}
+
+const FAKE_SU: &str = r#"printf "%s " "$@" > "$(dirname "$0")/arguments"
+read -r password
+if [ "$password" != "secret" ]; then
+    echo "su: Authentication failure" >&2
+    exit 1
+fi
+[ "$1" = "-c" ] || exit 2
+exec sh -c "$2"
+"#;
+
+#[tokio::test]
+async fn su_invoker_passes_environment_and_stdin_verbatim() {
+    let directory = TemporaryDirectory::new("su");
+    let su_path = directory.join("su");
+    write_script(&su_path, FAKE_SU);
+    let plugin_path = directory.join("echo plugin");
+    write_script(&plugin_path, "printf '%s|%s\\n' \"$CNI_ARGS\" \"$CNI_NETNS\"\ncat\n");
+
+    let injection = format!(
+        "K8S_POD_NAME=it's \"$(touch {})\"; `true`",
+        directory.join("pwned").display()
+    );
+    let environment = HashMap::from([
+        ("CNI_ARGS".to_owned(), injection.clone()),
+        ("CNI_NETNS".to_owned(), "/run/netns/with space".to_owned()),
+    ]);
+    let stdin = r#"{ "cniVersion": "1.0.0", "name": "it's" }"#;
+
+    let invoker = SuCniInvoker {
+        su_path,
+        password: "secret".into(),
+    };
+    let output = invoker.invoke(&plugin_path, environment, stdin.into()).await.unwrap();
+    assert!(output.success());
+    assert_eq!(output.stdout, format!("{injection}|/run/netns/with space\n{stdin}"));
+    assert!(!directory.join("pwned").exists());
+    let arguments = std::fs::read_to_string(directory.join("arguments")).unwrap();
+    assert!(!arguments.contains("CNI_") && !arguments.contains("K8S_POD_NAME") && !arguments.contains("with space"));
+}
+
+#[tokio::test]
+async fn su_invoker_reports_authentication_failure() {
+    let directory = TemporaryDirectory::new("su-auth");
+    let su_path = directory.join("su");
+    write_script(&su_path, FAKE_SU);
+
+    let invoker = SuCniInvoker {
+        su_path,
+        password: "wrong".into(),
+    };
+    let error = invoker
+        .invoke(&directory.join("plugin"), HashMap::new(), "{}".into())
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+}
+
+const FAKE_SUDO: &str = r#"printf "%s " "$@" > "$(dirname "$0")/arguments"
+while [ $# -gt 0 ]; do
+    case "$1" in
+        -n) non_interactive=1 ;;
+        -A) password="$("$SUDO_ASKPASS")" ;;
+        --preserve-env=*) ;;
+        --) shift; break ;;
+    esac
+    shift
+done
+if [ "$password" != "secret" ]; then
+    [ -n "$non_interactive" ] && echo "sudo: a password is required" >&2 || echo "sudo: incorrect password" >&2
+    exit 1
+fi
+exec "$@"
+"#;
+
+const ECHO_PLUGIN: &str = "printf '%s|%s|%s\\n' \"$CNI_COMMAND\" \"$CNI_ARGS\" \"$PRESERVED\"\ncat\n";
+
+#[tokio::test]
+async fn sudo_invoker_authenticates_with_askpass() {
+    let directory = TemporaryDirectory::new("sudo");
+    let sudo_path = directory.join("sudo");
+    write_script(&sudo_path, FAKE_SUDO);
+    let askpass_path = directory.join("askpass");
+    write_script(&askpass_path, "echo secret\n");
+    let plugin_path = directory.join("plugin");
+    write_script(&plugin_path, ECHO_PLUGIN);
+
+    let invoker = SudoCniInvoker {
+        sudo_path,
+        non_interactive: true,
+        askpass: Some(askpass_path),
+        preserved_environment: vec!["PRESERVED".into()],
+    };
+    let environment = HashMap::from([
+        ("CNI_COMMAND".to_owned(), "ADD".to_owned()),
+        ("CNI_ARGS".to_owned(), "IP=10.0.0.2;NAME=it's $HOME".to_owned()),
+    ]);
+    let output = invoker.invoke(&plugin_path, environment, "{}".into()).await.unwrap();
+    assert!(output.success());
+    assert_eq!(output.stdout, "ADD|IP=10.0.0.2;NAME=it's $HOME|\n{}");
+
+    let arguments = std::fs::read_to_string(directory.join("arguments")).unwrap();
+    assert!(arguments.starts_with("-n -A --preserve-env=PRESERVED -- /bin/sh"));
+    assert!(!arguments.contains("secret"));
+    assert!(!arguments.contains("CNI_") && !arguments.contains("IP=10.0.0.2"));
+}
+
+#[tokio::test]
+async fn sudo_invoker_reports_missing_authentication() {
+    let directory = TemporaryDirectory::new("sudo-auth");
+    let sudo_path = directory.join("sudo");
+    write_script(&sudo_path, FAKE_SUDO);
+
+    let invoker = SudoCniInvoker {
+        sudo_path,
+        non_interactive: true,
+        askpass: None,
+        preserved_environment: Vec::new(),
+    };
+    let error = invoker
+        .invoke(&directory.join("plugin"), HashMap::new(), "{}".into())
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+}
+
+#[tokio::test]
+async fn pkexec_invoker_passes_environment_through_cleared_environment() {
+    let directory = TemporaryDirectory::new("pkexec");
+    let pkexec_path = directory.join("pkexec");
+    write_script(
+        &pkexec_path,
+        "printf \"%s \" \"$@\" > \"$(dirname \"$0\")/arguments\"\nexec env -i \"$@\"\n",
+    );
+    let plugin_path = directory.join("plugin");
+    write_script(&plugin_path, ECHO_PLUGIN);
+
+    let invoker = PkexecCniInvoker { pkexec_path };
+    let environment = HashMap::from([("CNI_COMMAND".to_owned(), "DEL".to_owned())]);
+    let output = invoker.invoke(&plugin_path, environment, "{}".into()).await.unwrap();
+    assert!(output.success());
+    assert_eq!(output.stdout, "DEL||\n{}");
+    let arguments = std::fs::read_to_string(directory.join("arguments")).unwrap();
+    assert!(!arguments.contains("CNI_COMMAND") && !arguments.contains("DEL"));
+}
+
+#[tokio::test]
+async fn pkexec_invoker_reports_dismissed_authentication() {
+    let directory = TemporaryDirectory::new("pkexec-auth");
+    let pkexec_path = directory.join("pkexec");
+    write_script(
+        &pkexec_path,
+        "echo 'Error executing command as another user: Request dismissed' >&2\nexit 126\n",
+    );
+    let plugin_path = directory.join("plugin");
+    write_script(&plugin_path, "exit 126\n");
+
+    let invoker = PkexecCniInvoker { pkexec_path };
+    let error = invoker
+        .invoke(&plugin_path, HashMap::new(), "{}".into())
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+}
+
+#[tokio::test]
+async fn rootless_invoker_runs_plugin_in_its_namespaces() {
+    let directory = TemporaryDirectory::new("rootless");
+    let plugin_path = directory.join("plugin");
+    write_script(
+        &plugin_path,
+        "id -u\nreadlink /proc/self/ns/user /proc/self/ns/net \"$CNI_NETNS\"\n",
+    );
+
+    let invoker = RootlessCniInvoker::new().unwrap();
+    let expected_namespaces = [invoker.user_namespace_path(), invoker.network_namespace_path()]
+        .map(|path| std::fs::read_link(path).unwrap().to_string_lossy().into_owned());
+    assert_ne!(
+        expected_namespaces[1],
+        std::fs::read_link("/proc/self/ns/net").unwrap().to_string_lossy()
+    );
+    assert_eq!(
+        invoker.network_namespace(),
+        CniNetworkNamespace::LinuxNamespace(invoker.network_namespace_path().to_owned())
+    );
+    // the holder is a paused child of this process rather than some program found through PATH
+    let holder_directory = invoker.network_namespace_path().parent().unwrap().parent().unwrap();
+    let holder_status = std::fs::read_to_string(holder_directory.join("status")).unwrap();
+    assert!(holder_status.contains(&format!("PPid:\t{}\n", std::process::id())));
+
+    let environment = HashMap::from([(
+        "CNI_NETNS".to_owned(),
+        invoker.network_namespace_path().to_str().unwrap().to_owned(),
+    )]);
+    let output = invoker.invoke(&plugin_path, environment, "{}".into()).await.unwrap();
+    assert!(output.success(), "{}", output.stderr);
+    assert_eq!(
+        output.stdout,
+        format!(
+            "0\n{}\n{}\n{}\n",
+            expected_namespaces[0], expected_namespaces[1], expected_namespaces[1]
+        )
+    );
+}
@@ -0,0 +1,253 @@
+mod common;
+
+use std::{
+    collections::HashMap,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use common::TemporaryDirectory;
+use tokio_cni::invocation::{
+    CachingCniLocator, CniInvocationArguments, CniLocator, CniLocatorError, CniRejectedCandidate, CniRejectionReason,
+    DirectoryCniLocator, MappedCniLocator, PathCniLocator,
+};
+
+fn write_plugin(path: &Path) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, "#!/bin/sh\n").unwrap();
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[tokio::test]
+async fn path_locator_uses_first_matching_directory() {
+    let directory = TemporaryDirectory::new("path-locator");
+    let first = directory.join("first");
+    let second = directory.join("second");
+    write_plugin(&first.join("bridge"));
+    write_plugin(&second.join("bridge"));
+    write_plugin(&second.join("firewall"));
+    std::fs::create_dir_all(first.join("tuning")).unwrap();
+
+    let locator = PathCniLocator::new(vec![&first, &second]);
+    assert_eq!(locator.locate("bridge").await, Ok(first.join("bridge")));
+    assert_eq!(locator.locate("firewall").await, Ok(second.join("firewall")));
+    assert_eq!(
+        locator.locate("tuning").await,
+        Err(CniLocatorError {
+            plugin_type: "tuning".into(),
+            rejected_candidates: vec![
+                CniRejectedCandidate {
+                    path: first.join("tuning"),
+                    reason: CniRejectionReason::NotRegularFile,
+                },
+                CniRejectedCandidate {
+                    path: second.join("tuning"),
+                    reason: CniRejectionReason::DoesNotExist,
+                },
+            ],
+        })
+    );
+    assert_eq!(locator.search_paths(), [first, second]);
+}
+
+#[test]
+fn path_locator_shares_paths_with_arguments_and_environment() {
+    let mut arguments = CniInvocationArguments::new();
+    arguments.paths(vec!["/opt/cni/bin", "/usr/libexec/cni"]);
+    let expected = [PathBuf::from("/opt/cni/bin"), PathBuf::from("/usr/libexec/cni")];
+    assert_eq!(PathCniLocator::from_arguments(&arguments).search_paths(), expected);
+
+    assert_eq!(
+        PathCniLocator::from_cni_path("/opt/cni/bin:/usr/libexec/cni").search_paths(),
+        expected
+    );
+    assert!(PathCniLocator::from_cni_path("").search_paths().is_empty());
+}
+
+#[tokio::test]
+async fn directory_locator_does_not_match_substrings() {
+    let directory = TemporaryDirectory::new("directory-locator");
+    write_plugin(&directory.join("bridge.bak"));
+    write_plugin(&directory.join("host-local"));
+    write_plugin(&directory.join("host"));
+
+    let locator = DirectoryCniLocator {
+        directory_path: directory.to_path_buf(),
+        suffixes: Vec::new(),
+    };
+    assert_eq!(locator.locate("host").await, Ok(directory.join("host")));
+    assert_eq!(
+        locator.locate("bridge").await.unwrap_err().rejected_candidates,
+        [CniRejectedCandidate {
+            path: directory.join("bridge"),
+            reason: CniRejectionReason::DoesNotExist,
+        }]
+    );
+}
+
+#[tokio::test]
+async fn directory_locator_tries_suffixes_in_order_and_skips_non_executables() {
+    let directory = TemporaryDirectory::new("directory-locator-suffixes");
+    std::fs::write(directory.join("bridge"), "not executable").unwrap();
+    write_plugin(&directory.join("bridge.sh"));
+    write_plugin(&directory.join("bridge-v2"));
+
+    let locator = DirectoryCniLocator {
+        directory_path: directory.to_path_buf(),
+        suffixes: vec!["-v1".into(), ".sh".into(), "-v2".into()],
+    };
+    let error = DirectoryCniLocator {
+        directory_path: directory.to_path_buf(),
+        suffixes: Vec::new(),
+    }
+    .locate("bridge")
+    .await
+    .unwrap_err();
+    assert_eq!(error.rejected_candidates[0].reason, CniRejectionReason::NotExecutable);
+
+    assert_eq!(locator.locate("bridge").await, Ok(directory.join("bridge.sh")));
+}
+
+#[tokio::test]
+async fn locators_reject_plugin_types_outside_their_directories() {
+    let directory = TemporaryDirectory::new("locator-traversal");
+    write_plugin(&directory.join("bin").join("bridge"));
+    write_plugin(&directory.join("escaped"));
+
+    let bin_directory = directory.join("bin");
+    let path_locator = PathCniLocator::new(vec![&bin_directory]);
+    let directory_locator = DirectoryCniLocator {
+        directory_path: bin_directory.clone(),
+        suffixes: vec![".sh".into()],
+    };
+    let absolute_plugin_type = directory.join("escaped").to_str().unwrap().to_owned();
+
+    for plugin_type in [
+        "",
+        ".",
+        "..",
+        "../escaped",
+        "nested/bridge",
+        absolute_plugin_type.as_str(),
+    ] {
+        let expected = Err(CniLocatorError {
+            plugin_type: plugin_type.into(),
+            rejected_candidates: vec![CniRejectedCandidate {
+                path: PathBuf::from(plugin_type),
+                reason: CniRejectionReason::InvalidPluginType,
+            }],
+        });
+        assert_eq!(path_locator.locate(plugin_type).await, expected);
+        assert_eq!(directory_locator.locate(plugin_type).await, expected);
+    }
+    assert_eq!(path_locator.locate("bridge").await, Ok(bin_directory.join("bridge")));
+}
+
+struct CountingLocator<L: CniLocator> {
+    locator: L,
+    lookups: AtomicUsize,
+}
+
+impl<L: CniLocator> CountingLocator<L> {
+    fn new(locator: L) -> Self {
+        Self {
+            locator,
+            lookups: AtomicUsize::new(0),
+        }
+    }
+}
+
+#[async_trait]
+impl<L: CniLocator + Send + Sync> CniLocator for CountingLocator<L> {
+    async fn locate(&self, plugin_type: &str) -> Result<PathBuf, CniLocatorError> {
+        self.lookups.fetch_add(1, Ordering::SeqCst);
+        self.locator.locate(plugin_type).await
+    }
+
+    fn search_paths(&self) -> &[PathBuf] {
+        self.locator.search_paths()
+    }
+}
+
+fn locator_lookups<L: CniLocator + Send + Sync>(locator: &CachingCniLocator<CountingLocator<L>>) -> usize {
+    locator.locator().lookups.load(Ordering::SeqCst)
+}
+
+#[tokio::test]
+async fn caching_locator_invalidates_on_directory_change() {
+    let directory = TemporaryDirectory::new("caching-locator");
+    write_plugin(&directory.join("bridge"));
+
+    let locator = CachingCniLocator::new(
+        CountingLocator::new(PathCniLocator::new(vec![directory.to_path_buf()])),
+        Duration::from_secs(3600),
+    );
+    assert!(locator.is_watching());
+    assert_eq!(locator.search_paths(), [directory.to_path_buf()]);
+
+    assert_eq!(locator.locate("bridge").await, Ok(directory.join("bridge")));
+    assert_eq!(locator.locate("bridge").await, Ok(directory.join("bridge")));
+    assert_eq!(locator_lookups(&locator), 1);
+
+    write_plugin(&directory.join("firewall"));
+    assert_eq!(locator.locate("bridge").await, Ok(directory.join("bridge")));
+    assert_eq!(locator_lookups(&locator), 2);
+
+    std::fs::remove_file(directory.join("bridge")).unwrap();
+    assert!(locator.locate("bridge").await.is_err());
+    assert!(locator.locate("bridge").await.is_err());
+    assert_eq!(locator_lookups(&locator), 4);
+}
+
+#[tokio::test]
+async fn caching_locator_falls_back_to_time_to_live_once_directory_is_removed() {
+    let directory = TemporaryDirectory::new("caching-locator-removed");
+    write_plugin(&directory.join("bridge"));
+
+    let locator = CachingCniLocator::new(
+        CountingLocator::new(PathCniLocator::new(vec![directory.to_path_buf()])),
+        Duration::from_millis(100),
+    );
+    assert_eq!(locator.locate("bridge").await, Ok(directory.join("bridge")));
+    assert_eq!(locator_lookups(&locator), 1);
+
+    std::fs::remove_dir_all(&directory).unwrap();
+    assert!(locator.locate("bridge").await.is_err());
+    assert!(!locator.is_watching());
+
+    write_plugin(&directory.join("bridge"));
+    assert_eq!(locator.locate("bridge").await, Ok(directory.join("bridge")));
+    assert_eq!(locator.locate("bridge").await, Ok(directory.join("bridge")));
+    assert_eq!(locator_lookups(&locator), 3);
+
+    std::fs::remove_file(directory.join("bridge")).unwrap();
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert!(locator.locate("bridge").await.is_err());
+    assert_eq!(locator_lookups(&locator), 4);
+}
+
+#[tokio::test]
+async fn caching_locator_falls_back_to_time_to_live() {
+    let locator = CachingCniLocator::new(
+        CountingLocator::new(MappedCniLocator {
+            lookup_map: HashMap::from([("bridge".into(), PathBuf::from("/mock/bridge"))]),
+        }),
+        Duration::from_millis(100),
+    );
+    assert!(!locator.is_watching());
+
+    locator.locate("bridge").await.unwrap();
+    locator.locate("bridge").await.unwrap();
+    assert_eq!(locator_lookups(&locator), 1);
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    locator.locate("bridge").await.unwrap();
+    assert_eq!(locator_lookups(&locator), 2);
+
+    locator.invalidate();
+    locator.locate("bridge").await.unwrap();
+    assert_eq!(locator_lookups(&locator), 3);
+}
@@ -0,0 +1,778 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use async_trait::async_trait;
+use serde_json::Value;
+use tokio::io;
+use tokio_cni::{
+    cache::{CniCache, CniCacheKey, MemoryCniCache},
+    capabilities::{CniCapabilityArgs, CniPortMapping},
+    invocation::{
+        CniInvocationArguments, CniInvocationError, CniInvocationTarget, CniInvoker, CniInvokerOutput, CniSkipReason,
+        MappedCniLocator, PathCniLocator,
+    },
+    plugins::{CniDeserializable, CniPluginList},
+    runtime::{invoke, invoke_cached, negotiate_cni_version},
+    types::{
+        CniArg, CniAttachment, CniContainerId, CniInterfaceName, CniName, CniNetworkNamespace, CniOperation, CniVersion,
+    },
+};
+
+struct MockInvocation {
+    program: PathBuf,
+    environment: HashMap<String, String>,
+    stdin: Value,
+}
+
+#[derive(Default)]
+struct MockCniInvoker {
+    outputs: HashMap<String, CniInvokerOutput>,
+    invocations: Mutex<Vec<MockInvocation>>,
+}
+
+impl MockCniInvoker {
+    fn new() -> Self {
+        Self::default()
+    }
+
+    fn output(mut self, command: &str, output: &str) -> Self {
+        self.outputs.insert(command.into(), mock_output(0, output, ""));
+        self
+    }
+
+    fn plugin_output(mut self, command: &str, program: &str, output: &str) -> Self {
+        self.outputs
+            .insert(format!("{command} {program}"), mock_output(0, output, ""));
+        self
+    }
+
+    fn plugin_failure(mut self, command: &str, program: &str, output: &str) -> Self {
+        self.outputs
+            .insert(format!("{command} {program}"), mock_output(1, output, ""));
+        self
+    }
+
+    fn raw_output(mut self, command: &str, output: CniInvokerOutput) -> Self {
+        self.outputs.insert(command.into(), output);
+        self
+    }
+
+    fn take_invocations(&self) -> Vec<MockInvocation> {
+        std::mem::take(&mut self.invocations.lock().unwrap())
+    }
+}
+
+#[async_trait]
+impl CniInvoker for MockCniInvoker {
+    async fn invoke(
+        &self,
+        program: &Path,
+        environment: HashMap<String, String>,
+        stdin: String,
+    ) -> Result<CniInvokerOutput, io::Error> {
+        let command = environment.get("CNI_COMMAND").unwrap();
+        let output = self
+            .outputs
+            .get(&format!("{command} {}", program.display()))
+            .or_else(|| self.outputs.get(command))
+            .cloned()
+            .unwrap_or_else(|| mock_output(0, "", ""));
+        self.invocations.lock().unwrap().push(MockInvocation {
+            program: program.to_owned(),
+            environment,
+            stdin: serde_json::from_str(&stdin).unwrap(),
+        });
+        Ok(output)
+    }
+}
+
+fn mock_output(exit_code: i32, stdout: &str, stderr: &str) -> CniInvokerOutput {
+    CniInvokerOutput {
+        exit_code: Some(exit_code),
+        stdout: stdout.into(),
+        stderr: stderr.into(),
+    }
+}
+
+const ADD_RESULT: &str = r#"{
+    "cniVersion": "1.0.0",
+    "interfaces": [{ "name": "eth0", "sandbox": "/var/run/netns/test" }],
+    "ips": [{ "address": "10.0.0.2/24", "gateway": "10.0.0.1", "interface": 0 }]
+}"#;
+
+fn plugin_list() -> CniPluginList {
+    CniPluginList::from_string(
+        r#"{
+            "cniVersion": "1.0.0",
+            "name": "testnet",
+            "plugins": [{ "type": "bridge" }, { "type": "firewall" }]
+        }"#,
+    )
+    .unwrap()
+}
+
+fn locator() -> MappedCniLocator {
+    MappedCniLocator {
+        lookup_map: HashMap::from([
+            ("bridge".into(), PathBuf::from("/mock/bridge")),
+            ("firewall".into(), PathBuf::from("/mock/firewall")),
+        ]),
+    }
+}
+
+fn arguments() -> CniInvocationArguments {
+    let mut arguments = CniInvocationArguments::new();
+    arguments
+        .container_id(CniContainerId::new("container").unwrap())
+        .interface_name(CniInterfaceName::new("eth0").unwrap());
+    arguments
+}
+
+fn cache_key() -> CniCacheKey {
+    CniCacheKey::new(
+        CniName::new("testnet").unwrap(),
+        CniContainerId::new("container").unwrap(),
+        CniInterfaceName::new("eth0").unwrap(),
+    )
+}
+
+#[tokio::test]
+async fn cached_invocation_round_trips_add_check_delete() {
+    let plugin_list = plugin_list();
+    let target = CniInvocationTarget::PluginList(&plugin_list);
+    let invoker = MockCniInvoker::new().output("ADD", ADD_RESULT);
+    let cache = MemoryCniCache::new();
+
+    let add_result = invoke_cached(CniOperation::Add, &arguments(), &target, &invoker, &locator(), &cache)
+        .await
+        .unwrap();
+    let attachment = add_result.attachment.unwrap();
+    assert_eq!(cache.get(&cache_key()).await.unwrap(), Some(attachment.clone()));
+    invoker.take_invocations();
+
+    invoke_cached(CniOperation::Check, &arguments(), &target, &invoker, &locator(), &cache)
+        .await
+        .unwrap();
+    let expected_prev_result = serde_json::to_value(&attachment).unwrap();
+    for invocation in invoker.take_invocations() {
+        assert_eq!(invocation.stdin["prevResult"], expected_prev_result);
+    }
+
+    invoke_cached(
+        CniOperation::Delete,
+        &arguments(),
+        &target,
+        &invoker,
+        &locator(),
+        &cache,
+    )
+    .await
+    .unwrap();
+    let invocations = invoker.take_invocations();
+    assert_eq!(invocations[0].program, PathBuf::from("/mock/firewall"));
+    assert_eq!(invocations[0].environment["CNI_COMMAND"], "DEL");
+    assert_eq!(invocations[0].stdin["prevResult"], expected_prev_result);
+    assert_eq!(cache.get(&cache_key()).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn cached_invocation_prefers_explicit_attachment() {
+    let plugin_list = plugin_list();
+    let target = CniInvocationTarget::PluginList(&plugin_list);
+    let invoker = MockCniInvoker::new();
+    let cache = MemoryCniCache::new();
+    let cached_attachment = serde_json::from_str(ADD_RESULT).unwrap();
+    cache.insert(&cache_key(), &cached_attachment).await.unwrap();
+
+    let mut explicit_attachment = cached_attachment.clone();
+    explicit_attachment.ips.clear();
+    let mut arguments = arguments();
+    arguments.attachment(explicit_attachment.clone());
+
+    invoke_cached(CniOperation::Check, &arguments, &target, &invoker, &locator(), &cache)
+        .await
+        .unwrap();
+    for invocation in invoker.take_invocations() {
+        assert_eq!(
+            invocation.stdin["prevResult"],
+            serde_json::to_value(&explicit_attachment).unwrap()
+        );
+    }
+}
+
+#[tokio::test]
+async fn check_is_skipped_when_disabled() {
+    let mut plugin_list = plugin_list();
+    plugin_list.cni_version = CniVersion::new(1, 1, 0);
+    plugin_list.disable_check = Some(true);
+    let target = CniInvocationTarget::PluginList(&plugin_list);
+    let invoker = MockCniInvoker::new();
+
+    let result = invoke(CniOperation::Check, &arguments(), &target, &invoker, &locator())
+        .await
+        .unwrap();
+    assert_eq!(result.skip_reason, Some(CniSkipReason::CheckDisabled));
+    assert!(invoker.take_invocations().is_empty());
+
+    invoke(
+        CniOperation::GarbageCollect,
+        &arguments(),
+        &target,
+        &invoker,
+        &locator(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(invoker.take_invocations().len(), 2);
+}
+
+#[tokio::test]
+async fn garbage_collect_is_skipped_when_disabled() {
+    let mut plugin_list = plugin_list();
+    plugin_list.cni_version = CniVersion::new(1, 1, 0);
+    plugin_list.disable_gc = Some(true);
+    let target = CniInvocationTarget::PluginList(&plugin_list);
+    let invoker = MockCniInvoker::new();
+
+    let result = invoke(
+        CniOperation::GarbageCollect,
+        &arguments(),
+        &target,
+        &invoker,
+        &locator(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(result.skip_reason, Some(CniSkipReason::GarbageCollectDisabled));
+    assert!(invoker.take_invocations().is_empty());
+
+    let result = invoke(CniOperation::Check, &arguments(), &target, &invoker, &locator())
+        .await
+        .unwrap();
+    assert_eq!(result.skip_reason, None);
+    assert_eq!(invoker.take_invocations().len(), 2);
+}
+
+fn multi_version_plugin_list() -> CniPluginList {
+    CniPluginList::from_string(
+        r#"{
+            "cniVersion": "1.0.0",
+            "cniVersions": ["0.3.1", "0.4.0", "1.0.0", "0.10.0"],
+            "name": "testnet",
+            "plugins": [{ "type": "bridge" }, { "type": "firewall" }]
+        }"#,
+    )
+    .unwrap()
+}
+
+#[tokio::test]
+async fn negotiation_picks_highest_common_version() {
+    let plugin_list = multi_version_plugin_list();
+    let target = CniInvocationTarget::PluginList(&plugin_list);
+    let invoker = MockCniInvoker::new()
+        .plugin_output(
+            "VERSION",
+            "/mock/bridge",
+            r#"{ "cniVersion": "1.0.0", "supportedVersions": ["0.3.1", "0.4.0", "0.10.0", "1.0.0"] }"#,
+        )
+        .plugin_output(
+            "VERSION",
+            "/mock/firewall",
+            r#"{ "cniVersion": "0.4.0", "supportedVersions": ["0.3.1", "0.4.0", "0.10.0"] }"#,
+        );
+
+    let cni_version = negotiate_cni_version(&arguments(), &target, &invoker, &locator())
+        .await
+        .unwrap();
+    assert_eq!(cni_version, CniVersion::parse("0.10.0").unwrap());
+
+    let mut arguments = arguments();
+    arguments.negotiate_cni_version(true);
+    invoker.take_invocations();
+    invoke(CniOperation::Add, &arguments, &target, &invoker, &locator())
+        .await
+        .unwrap();
+    let invocations = invoker.take_invocations();
+    assert_eq!(invocations.len(), 4);
+    for invocation in &invocations[2..] {
+        assert_eq!(invocation.environment["CNI_COMMAND"], "ADD");
+        assert_eq!(invocation.stdin["cniVersion"], "0.10.0");
+    }
+}
+
+#[tokio::test]
+async fn negotiation_fails_naming_plugin_without_overlap() {
+    let plugin_list = multi_version_plugin_list();
+    let target = CniInvocationTarget::PluginList(&plugin_list);
+    let invoker = MockCniInvoker::new()
+        .plugin_output(
+            "VERSION",
+            "/mock/bridge",
+            r#"{ "cniVersion": "1.0.0", "supportedVersions": ["0.4.0", "1.0.0"] }"#,
+        )
+        .plugin_output(
+            "VERSION",
+            "/mock/firewall",
+            r#"{ "cniVersion": "1.1.0", "supportedVersions": ["1.1.0"] }"#,
+        );
+
+    match negotiate_cni_version(&arguments(), &target, &invoker, &locator()).await {
+        Err(CniInvocationError::NoCommonCniVersion { plugin_type }) => assert_eq!(plugin_type, "firewall"),
+        other => panic!("unexpected negotiation outcome: {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn operations_are_gated_by_cni_version() {
+    let plugin_list = plugin_list();
+    let target = CniInvocationTarget::PluginList(&plugin_list);
+    let invoker = MockCniInvoker::new();
+
+    for operation in [CniOperation::Status, CniOperation::GarbageCollect] {
+        match invoke(operation, &arguments(), &target, &invoker, &locator()).await {
+            Err(CniInvocationError::OperationNotSupportedByVersion {
+                operation: rejected_operation,
+                cni_version,
+            }) => {
+                assert_eq!(rejected_operation, operation);
+                assert_eq!(cni_version, CniVersion::new(1, 0, 0));
+            }
+            other => panic!("unexpected invocation outcome: {other:?}"),
+        }
+    }
+
+    // negotiation can't help if the plugin list doesn't allow a version that has the operation
+    let mut negotiating_arguments = arguments();
+    negotiating_arguments.negotiate_cni_version(true);
+    assert!(matches!(
+        invoke(
+            CniOperation::GarbageCollect,
+            &negotiating_arguments,
+            &target,
+            &invoker,
+            &locator()
+        )
+        .await,
+        Err(CniInvocationError::OperationNotSupportedByVersion { .. })
+    ));
+    assert!(invoker.take_invocations().is_empty());
+
+    let mut arguments = arguments();
+    arguments.cni_version(CniVersion::new(0, 3, 1));
+    assert!(matches!(
+        invoke(CniOperation::Check, &arguments, &target, &invoker, &locator()).await,
+        Err(CniInvocationError::OperationNotSupportedByVersion { .. })
+    ));
+    assert!(invoker.take_invocations().is_empty());
+
+    arguments.cni_version(CniVersion::new(1, 1, 0));
+    invoke(CniOperation::Status, &arguments, &target, &invoker, &locator())
+        .await
+        .unwrap();
+    assert_eq!(invoker.take_invocations().len(), 2);
+}
+
+#[tokio::test]
+async fn prev_result_is_converted_to_the_cni_version() {
+    let mut plugin_list = plugin_list();
+    plugin_list.cni_version = CniVersion::new(0, 4, 0);
+    let target = CniInvocationTarget::PluginList(&plugin_list);
+    let invoker = MockCniInvoker::new().plugin_output(
+        "ADD",
+        "/mock/bridge",
+        r#"{
+            "cniVersion": "0.4.0",
+            "interfaces": [{ "name": "eth0", "sandbox": "/var/run/netns/test" }],
+            "ips": [{ "version": "4", "address": "10.0.0.2/24", "gateway": "10.0.0.1", "interface": 0 }]
+        }"#,
+    );
+
+    let result = invoke(CniOperation::Add, &arguments(), &target, &invoker, &locator())
+        .await
+        .unwrap();
+    assert_eq!(result.attachment.unwrap().cni_version, CniVersion::new(1, 0, 0));
+
+    let invocations = invoker.take_invocations();
+    let prev_result = &invocations[1].stdin["prevResult"];
+    assert_eq!(prev_result["cniVersion"], "0.4.0");
+    assert_eq!(prev_result["ips"][0]["version"], "4");
+}
+
+#[tokio::test]
+async fn cni_args_are_passed_through_the_environment() {
+    let plugin_list = plugin_list();
+    let target = CniInvocationTarget::PluginList(&plugin_list);
+    let invoker = MockCniInvoker::new();
+
+    invoke(CniOperation::Add, &arguments(), &target, &invoker, &locator())
+        .await
+        .unwrap();
+    assert!(!invoker.take_invocations()[0].environment.contains_key("CNI_ARGS"));
+
+    let mut arguments = arguments();
+    arguments.cni_args(vec![
+        CniArg::new("IgnoreUnknown", "1").unwrap(),
+        CniArg::new("K8S_POD_NAME", "pod").unwrap(),
+    ]);
+    invoke(CniOperation::Add, &arguments, &target, &invoker, &locator())
+        .await
+        .unwrap();
+    for invocation in invoker.take_invocations() {
+        assert_eq!(invocation.environment["CNI_ARGS"], "IgnoreUnknown=1;K8S_POD_NAME=pod");
+    }
+}
+
+#[tokio::test]
+async fn capability_args_are_filtered_into_runtime_config() {
+    let plugin_list = CniPluginList::from_string(
+        r#"{
+            "cniVersion": "1.0.0",
+            "name": "testnet",
+            "plugins": [
+                { "type": "bridge" },
+                { "type": "firewall", "capabilities": { "portMappings": true, "mac": false } }
+            ]
+        }"#,
+    )
+    .unwrap();
+    let target = CniInvocationTarget::PluginList(&plugin_list);
+    let invoker = MockCniInvoker::new();
+
+    invoke(CniOperation::Add, &arguments(), &target, &invoker, &locator())
+        .await
+        .unwrap();
+    for invocation in invoker.take_invocations() {
+        assert_eq!(invocation.stdin.get("runtimeConfig"), None);
+    }
+
+    let mut capability_args = CniCapabilityArgs::new();
+    capability_args
+        .port_mappings(vec![CniPortMapping {
+            host_port: 8080,
+            container_port: 80,
+            protocol: "tcp".into(),
+            host_ip: None,
+        }])
+        .mac("0a:58:0a:00:00:02");
+    let mut arguments = arguments();
+    arguments.capability_args(capability_args);
+
+    invoke(CniOperation::Add, &arguments, &target, &invoker, &locator())
+        .await
+        .unwrap();
+    let invocations = invoker.take_invocations();
+    assert_eq!(invocations[0].stdin.get("runtimeConfig"), None);
+    assert_eq!(
+        invocations[1].stdin["runtimeConfig"],
+        serde_json::json!({ "portMappings": [{ "hostPort": 8080, "containerPort": 80, "protocol": "tcp" }] })
+    );
+}
+
+const PLUGIN_ERROR: &str = r#"{ "cniVersion": "1.0.0", "code": 7, "msg": "plugin failed" }"#;
+
+fn three_plugin_list() -> CniPluginList {
+    CniPluginList::from_string(
+        r#"{
+            "cniVersion": "1.0.0",
+            "name": "testnet",
+            "plugins": [{ "type": "bridge" }, { "type": "firewall" }, { "type": "tuning" }]
+        }"#,
+    )
+    .unwrap()
+}
+
+fn three_plugin_locator() -> MappedCniLocator {
+    let mut locator = locator();
+    locator
+        .lookup_map
+        .insert("tuning".into(), PathBuf::from("/mock/tuning"));
+    locator
+}
+
+#[tokio::test]
+async fn failed_add_is_rolled_back_when_enabled() {
+    let plugin_list = three_plugin_list();
+    let target = CniInvocationTarget::PluginList(&plugin_list);
+    let invoker = MockCniInvoker::new()
+        .plugin_output("ADD", "/mock/bridge", ADD_RESULT)
+        .plugin_failure("ADD", "/mock/tuning", PLUGIN_ERROR)
+        .plugin_failure("DEL", "/mock/bridge", PLUGIN_ERROR);
+
+    let result = invoke(
+        CniOperation::Add,
+        &arguments(),
+        &target,
+        &invoker,
+        &three_plugin_locator(),
+    )
+    .await;
+    assert!(matches!(result, Err(CniInvocationError::PluginProducedError { .. })));
+    assert_eq!(invoker.take_invocations().len(), 3);
+
+    let mut arguments = arguments();
+    arguments.rollback_on_failure(true);
+    let result = invoke(
+        CniOperation::Add,
+        &arguments,
+        &target,
+        &invoker,
+        &three_plugin_locator(),
+    )
+    .await;
+    match result {
+        Err(CniInvocationError::AddRolledBack {
+            failure,
+            rollback_failures,
+        }) => {
+            assert_eq!(failure.plugin_type, "tuning");
+            assert!(matches!(failure.error, CniInvocationError::PluginProducedError { .. }));
+            assert_eq!(rollback_failures.len(), 1);
+            assert_eq!(rollback_failures[0].plugin_type, "bridge");
+        }
+        other => panic!("unexpected invocation outcome: {other:?}"),
+    }
+
+    let invocations = invoker.take_invocations();
+    let expected_prev_result =
+        serde_json::to_value(serde_json::from_str::<CniAttachment>(ADD_RESULT).unwrap()).unwrap();
+    let rollback = invocations[3..]
+        .iter()
+        .map(|invocation| (invocation.program.to_str().unwrap(), &invocation.stdin["prevResult"]))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        rollback,
+        [
+            ("/mock/firewall", &expected_prev_result),
+            ("/mock/bridge", &expected_prev_result)
+        ]
+    );
+    for invocation in &invocations[3..] {
+        assert_eq!(invocation.environment["CNI_COMMAND"], "DEL");
+    }
+}
+
+#[tokio::test]
+async fn delete_survives_failed_negotiation() {
+    let plugin_list = plugin_list();
+    let target = CniInvocationTarget::PluginList(&plugin_list);
+    let invoker = MockCniInvoker::new().plugin_output(
+        "VERSION",
+        "/mock/firewall",
+        r#"{ "cniVersion": "1.0.0", "supportedVersions": ["1.0.0"] }"#,
+    );
+    let locator = MappedCniLocator {
+        lookup_map: HashMap::from([("firewall".into(), PathBuf::from("/mock/firewall"))]),
+    };
+
+    let mut arguments = arguments();
+    arguments.negotiate_cni_version(true);
+    match invoke(CniOperation::Delete, &arguments, &target, &invoker, &locator).await {
+        Err(CniInvocationError::PluginsFailed {
+            failures,
+            succeeded_plugins,
+        }) => {
+            assert_eq!(failures.len(), 1);
+            assert_eq!(failures[0].plugin_type, "bridge");
+            assert!(matches!(
+                failures[0].error,
+                CniInvocationError::PluginNotFoundByLocator(_)
+            ));
+            assert_eq!(succeeded_plugins, ["firewall"]);
+        }
+        other => panic!("unexpected invocation outcome: {other:?}"),
+    }
+    let deletions = invoker
+        .take_invocations()
+        .into_iter()
+        .filter(|invocation| invocation.environment["CNI_COMMAND"] == "DEL")
+        .collect::<Vec<_>>();
+    assert_eq!(deletions.len(), 1);
+    assert_eq!(deletions[0].program, PathBuf::from("/mock/firewall"));
+    assert_eq!(deletions[0].stdin["cniVersion"], "1.0.0");
+
+    assert!(matches!(
+        invoke(CniOperation::Add, &arguments, &target, &invoker, &locator).await,
+        Err(CniInvocationError::PluginNotFoundByLocator(_))
+    ));
+}
+
+#[tokio::test]
+async fn delete_and_garbage_collect_continue_past_failures() {
+    let mut plugin_list = three_plugin_list();
+    plugin_list.cni_version = CniVersion::new(1, 1, 0);
+    let target = CniInvocationTarget::PluginList(&plugin_list);
+    let invoker = MockCniInvoker::new()
+        .plugin_failure("DEL", "/mock/tuning", PLUGIN_ERROR)
+        .plugin_failure("DEL", "/mock/bridge", PLUGIN_ERROR)
+        .plugin_failure("GC", "/mock/firewall", PLUGIN_ERROR);
+
+    match invoke(
+        CniOperation::Delete,
+        &arguments(),
+        &target,
+        &invoker,
+        &three_plugin_locator(),
+    )
+    .await
+    {
+        Err(CniInvocationError::PluginsFailed {
+            failures,
+            succeeded_plugins,
+        }) => {
+            let failed_plugins = failures
+                .iter()
+                .map(|failure| failure.plugin_type.as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(failed_plugins, ["tuning", "bridge"]);
+            assert_eq!(succeeded_plugins, ["firewall"]);
+        }
+        other => panic!("unexpected invocation outcome: {other:?}"),
+    }
+    assert_eq!(invoker.take_invocations().len(), 3);
+
+    match invoke(
+        CniOperation::GarbageCollect,
+        &arguments(),
+        &target,
+        &invoker,
+        &three_plugin_locator(),
+    )
+    .await
+    {
+        Err(CniInvocationError::PluginsFailed {
+            failures,
+            succeeded_plugins,
+        }) => {
+            assert_eq!(failures.len(), 1);
+            assert_eq!(failures[0].plugin_type, "firewall");
+            assert_eq!(succeeded_plugins, ["bridge", "tuning"]);
+        }
+        other => panic!("unexpected invocation outcome: {other:?}"),
+    }
+    assert_eq!(invoker.take_invocations().len(), 3);
+}
+
+#[tokio::test]
+async fn successful_plugin_stderr_is_not_parsed() {
+    let plugin_list = plugin_list();
+    let target = CniInvocationTarget::PluginList(&plugin_list);
+    let invoker = MockCniInvoker::new().raw_output(
+        "ADD",
+        mock_output(0, ADD_RESULT, &"a very long log line on stderr\n".repeat(100)),
+    );
+
+    let result = invoke(CniOperation::Add, &arguments(), &target, &invoker, &locator())
+        .await
+        .unwrap();
+    assert_eq!(result.attachment, Some(serde_json::from_str(ADD_RESULT).unwrap()));
+}
+
+#[tokio::test]
+async fn failed_plugin_error_keeps_stderr() {
+    let plugin_list = plugin_list();
+    let target = CniInvocationTarget::PluginList(&plugin_list);
+    let invoker = MockCniInvoker::new().raw_output("ADD", mock_output(1, PLUGIN_ERROR, "diagnostics"));
+
+    match invoke(CniOperation::Add, &arguments(), &target, &invoker, &locator()).await {
+        Err(CniInvocationError::PluginProducedError { error, stderr }) => {
+            assert_eq!(error.code, 7);
+            assert_eq!(stderr, "diagnostics");
+        }
+        other => panic!("unexpected invocation outcome: {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn failed_plugin_without_error_is_unrecognizable() {
+    let plugin_list = plugin_list();
+    let target = CniInvocationTarget::PluginList(&plugin_list);
+    let invoker = MockCniInvoker::new().raw_output("ADD", mock_output(2, ADD_RESULT, "panic"));
+
+    match invoke(CniOperation::Add, &arguments(), &target, &invoker, &locator()).await {
+        Err(CniInvocationError::PluginProducedUnrecognizableOutput {
+            exit_code,
+            stdout,
+            stderr,
+        }) => {
+            assert_eq!(exit_code, Some(2));
+            assert_eq!(stdout, ADD_RESULT);
+            assert_eq!(stderr, "panic");
+        }
+        other => panic!("unexpected invocation outcome: {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn only_versioned_output_is_converted() {
+    let plugin_list = plugin_list();
+    let target = CniInvocationTarget::PluginList(&plugin_list);
+
+    let invoker = MockCniInvoker::new().output("ADD", r#"{ "foo": 1 }"#);
+    assert!(matches!(
+        invoke(CniOperation::Add, &arguments(), &target, &invoker, &locator()).await,
+        Err(CniInvocationError::PluginProducedUnrecognizableOutput { .. })
+    ));
+
+    let invoker = MockCniInvoker::new().output("ADD", r#"{ "cniVersion": "1.0.0", "ips": "none" }"#);
+    assert!(matches!(
+        invoke(CniOperation::Add, &arguments(), &target, &invoker, &locator()).await,
+        Err(CniInvocationError::ConversionFailed(_))
+    ));
+}
+
+#[tokio::test]
+async fn invalid_network_namespace_is_rejected_before_add() {
+    let plugin_list = plugin_list();
+    let target = CniInvocationTarget::PluginList(&plugin_list);
+    let invoker = MockCniInvoker::new().output("ADD", ADD_RESULT);
+
+    let mut arguments = arguments();
+    arguments.network_namespace(CniNetworkNamespace::LinuxNamespace("/usr/libexec/cni".into()));
+    let result = invoke(CniOperation::Add, &arguments, &target, &invoker, &locator()).await;
+    assert!(matches!(result, Err(CniInvocationError::MalformedNetworkNamespace(_))));
+    assert!(invoker.take_invocations().is_empty());
+
+    invoke(CniOperation::Delete, &arguments, &target, &invoker, &locator())
+        .await
+        .unwrap();
+    assert_eq!(invoker.take_invocations().len(), 2);
+
+    arguments.network_namespace(CniNetworkNamespace::LinuxNamespace("/proc/self/ns/net".into()));
+    invoke(CniOperation::Add, &arguments, &target, &invoker, &locator())
+        .await
+        .unwrap();
+    assert_eq!(
+        invoker.take_invocations()[0].environment["CNI_NETNS"],
+        "/proc/self/ns/net"
+    );
+}
+
+#[tokio::test]
+async fn cni_path_defaults_to_locator_search_paths() {
+    let mut plugin_list = plugin_list();
+    for plugin in &mut plugin_list.plugins {
+        plugin.plugin_type = "sh".into();
+    }
+    let target = CniInvocationTarget::PluginList(&plugin_list);
+    let invoker = MockCniInvoker::new();
+    let path_locator = PathCniLocator::new(vec!["/bin", "/usr/bin"]);
+
+    invoke(CniOperation::Add, &arguments(), &target, &invoker, &path_locator)
+        .await
+        .unwrap();
+    for invocation in invoker.take_invocations() {
+        assert_eq!(invocation.program, PathBuf::from("/bin/sh"));
+        assert_eq!(invocation.environment["CNI_PATH"], "/bin:/usr/bin");
+    }
+
+    let mut arguments = arguments();
+    arguments.paths(vec!["/opt/cni/bin"]);
+    assert!(matches!(
+        invoke(CniOperation::Add, &arguments, &target, &invoker, &path_locator).await,
+        Err(CniInvocationError::ConflictingPluginPaths { .. })
+    ));
+    assert!(invoker.take_invocations().is_empty());
+}